@@ -0,0 +1,144 @@
+// Generates a maze without opening a window, walks a scripted player through it and dumps every frame.
+//
+// Usage: tenebris-sim [--seed N] [--config FILE] [--path FILE] [--steps N]
+//                     [--mode in-place|synchronous] [--memory proximity|line-of-sight]
+//                     [--format ascii|png] [--scale N] [--out DIR]
+//
+// A path file lists waypoints as `x y [wait]`, one per line; `#` starts a comment.
+// The player starts on the first waypoint, walks to each following one a tile per step,
+// and then stays in place for `wait` more steps.
+// Without a path file the player stands in the middle of the map for --steps steps.
+// --mode overrides the update mode from the config, to compare both on the same seed.
+// --memory does the same for the memory model.
+
+use std::{fs, path::PathBuf, process};
+
+use bevy::prelude::IVec2;
+use tenebris::{mapdump::*, simconfig::*, tilesim::Simulator, utils::WorldSeed};
+
+struct Args {
+	seed: WorldSeed,
+	config: PathBuf,
+	path: Option<PathBuf>,
+	steps: u32,
+	mode: Option<UpdateMode>,
+	memory: Option<MemoryModel>,
+	format: DumpFormat,
+	scale: u32,
+	out: PathBuf,
+}
+
+fn parse_args() -> Result<Args, String> {
+	let mut args = Args {
+		seed: WorldSeed::from_env_or_entropy(),
+		config: SIMULATOR_CONFIG_PATH.into(),
+		path: None,
+		steps: 20,
+		mode: None,
+		memory: None,
+		format: DumpFormat::Ascii,
+		scale: 4,
+		out: "sim_output".into(),
+	};
+	let mut it = std::env::args().skip(1);
+	while let Some(flag) = it.next() {
+		let mut value = || it.next().ok_or(format!("missing value for {flag}"));
+		let number = |v: String| v.parse::<u64>().map_err(|e| format!("{v}: {e}"));
+		match flag.as_str() {
+			"--seed" => args.seed = WorldSeed(number(value()?)?),
+			"--config" => args.config = value()?.into(),
+			"--path" => args.path = Some(value()?.into()),
+			"--steps" => args.steps = number(value()?)? as u32,
+			"--mode" => args.mode = Some(value()?.parse()?),
+			"--memory" => args.memory = Some(value()?.parse()?),
+			"--format" => args.format = value()?.parse()?,
+			"--scale" => args.scale = number(value()?)?.max(1) as u32,
+			"--out" => args.out = value()?.into(),
+			_ => return Err(format!("unknown argument {flag}")),
+		}
+	}
+	Ok(args)
+}
+
+// Expands the waypoints in a path file into one player position per simulator step.
+fn load_path(file: &PathBuf) -> Result<Vec<IVec2>, String> {
+	let text = fs::read_to_string(file).map_err(|e| format!("{}: {e}", file.display()))?;
+	let mut positions: Vec<IVec2> = Vec::new();
+	for (n, line) in text.lines().enumerate() {
+		let line = line.split('#').next().unwrap().trim();
+		if line.is_empty() {
+			continue;
+		}
+		let fields = line
+			.split_whitespace()
+			.map(|f| f.parse::<i32>())
+			.collect::<Result<Vec<_>, _>>()
+			.map_err(|e| format!("{}:{}: {e}", file.display(), n + 1))?;
+		let (target, wait) = match fields[..] {
+			[x, y] => (IVec2::new(x, y), 0),
+			[x, y, wait] if wait >= 0 => (IVec2::new(x, y), wait),
+			_ => return Err(format!("{}:{}: expected `x y [wait]`", file.display(), n + 1)),
+		};
+		if let Some(&from) = positions.last() {
+			let delta = target - from;
+			let len = delta.x.abs().max(delta.y.abs());
+			for t in 1..=len {
+				let p = from.as_vec2() + delta.as_vec2() * (t as f32 / len as f32);
+				positions.push(p.round().as_ivec2());
+			}
+		} else {
+			positions.push(target);
+		}
+		positions.extend(std::iter::repeat(target).take(wait as usize));
+	}
+	if positions.is_empty() {
+		return Err(format!("{}: path is empty", file.display()));
+	}
+	Ok(positions)
+}
+
+fn run(args: Args) -> Result<(), String> {
+	let mut config = SimulatorConfig::load(&args.config).map_err(|e| e.to_string())?;
+	if let Some(mode) = args.mode {
+		config.update_mode = mode;
+	}
+	if let Some(memory) = args.memory {
+		config.memory_model = memory;
+	}
+	let mut simulator = Simulator::new(config, args.seed);
+	simulator.post_init();
+
+	let path = match &args.path {
+		Some(file) => load_path(file)?,
+		None => vec![simulator.world_center(); args.steps as usize + 1],
+	};
+	// An infinite world is generated around wherever the player goes
+	if let Some(p) = path.iter().find(|p| !simulator.config.infinite && !simulator.in_bounds(**p)) {
+		return Err(format!("player path leaves the map at ({}, {})", p.x, p.y));
+	}
+
+	fs::create_dir_all(&args.out).map_err(|e| format!("{}: {e}", args.out.display()))?;
+	println!(
+		"Seed {}, writing {} {} frames to {}",
+		args.seed.0,
+		path.len(),
+		args.format,
+		args.out.display()
+	);
+	// Frame 0 is the freshly generated map, every following frame is one step of the automaton.
+	for (frame, &player_pos) in path.iter().enumerate() {
+		if frame > 0 {
+			simulator.step(player_pos);
+		}
+		let file = args.out.join(format!("frame_{frame:04}.{}", args.format.extension()));
+		write_frame(&simulator, player_pos, args.format, args.scale, &file)?;
+	}
+	Ok(())
+}
+
+fn main() {
+	if let Err(e) = parse_args().and_then(run) {
+		eprintln!("tenebris-sim: {e}");
+		process::exit(1);
+	}
+}
@@ -0,0 +1,94 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{carule::CaRuleConfig, utils::WorldSeed};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EnemyKind {
+	Ranger,
+	Wraith,
+	Goo,
+}
+
+// A region of the map with its own cave texture, look and inhabitants.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BiomeConfig {
+	pub name: String,
+	pub ca_rule: CaRuleConfig,
+	pub floor_weight: u32,
+	pub wall_weight: u32,
+	// Relative odds of the other kinds of fresh cells, next to floor_weight for open ones and wall_weight for solid ones.
+	pub water_weight: u32,
+	pub chasm_weight: u32,
+	pub rubble_weight: u32,
+	pub crystal_vein_weight: u32,
+	// Added to the cave atlas indices of wall and floor tiles, to pick another tile set from the same atlas.
+	pub wall_atlas_offset: i32,
+	pub floor_atlas_offset: i32,
+	// Relative odds of each kind of enemy spawning in this biome.
+	pub enemies: Vec<(EnemyKind, u32)>,
+}
+
+impl Default for BiomeConfig {
+	fn default() -> Self {
+		Self {
+			name: "cave".into(),
+			ca_rule: CaRuleConfig::default(),
+			floor_weight: 10,
+			wall_weight: 13,
+			water_weight: 0,
+			chasm_weight: 0,
+			rubble_weight: 0,
+			crystal_vein_weight: 0,
+			wall_atlas_offset: 0,
+			floor_atlas_offset: 0,
+			enemies: vec![(EnemyKind::Ranger, 1), (EnemyKind::Wraith, 1), (EnemyKind::Goo, 1)],
+		}
+	}
+}
+
+impl BiomeConfig {
+	// Total odds of a fresh cell being open or solid.
+	pub fn open_weight(&self) -> u32 {
+		self.floor_weight + self.water_weight + self.chasm_weight + self.rubble_weight
+	}
+
+	pub fn solid_weight(&self) -> u32 {
+		self.wall_weight + self.crystal_vein_weight
+	}
+}
+
+// Which of `n_biomes` biomes a tile belongs to. The map is split into the Voronoi regions of one site per
+// `size` x `size` square, placed at a random spot in its square, and every region gets a random biome.
+pub fn biome_at(seed: WorldSeed, size: u32, n_biomes: usize, loc: IVec2) -> usize {
+	if n_biomes <= 1 {
+		return 0;
+	}
+	let size = size as i32;
+	let square = IVec2::new(loc.x.div_euclid(size), loc.y.div_euclid(size));
+	let mut nearest = (i32::MAX, 0);
+	// The nearest site is almost always in one of the neighbouring squares
+	for dx in -1..=1 {
+		for dy in -1..=1 {
+			let square = square + IVec2::new(dx, dy);
+			let hash = site_hash(seed, square);
+			let jitter = IVec2::new((hash % size as u64) as i32, ((hash >> 16) % size as u64) as i32);
+			let offset = square * size + jitter - loc;
+			let dist = offset.dot(offset);
+			if dist < nearest.0 {
+				nearest = (dist, ((hash >> 32) % n_biomes as u64) as usize);
+			}
+		}
+	}
+	nearest.1
+}
+
+// SplitMix64 of the seed and square, cheap enough to run for every tile.
+fn site_hash(seed: WorldSeed, square: IVec2) -> u64 {
+	let key = ((square.x as u32 as u64) << 32) | square.y as u32 as u64;
+	let mut z = seed.0 ^ key.wrapping_add(0x9E37_79B9_7F4A_7C15);
+	z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+	z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+	z ^ (z >> 31)
+}
@@ -0,0 +1,258 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// Which cells around a cell count as its neighbours.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Neighbourhood {
+	// Square of the given radius; Moore(1) is the usual 8 surrounding cells
+	Moore(u32),
+	// Diamond of the given radius in the Manhattan metric; VonNeumann(1) is the 4 orthogonal cells
+	VonNeumann(u32),
+}
+
+impl Neighbourhood {
+	pub fn offsets(&self) -> Vec<IVec2> {
+		let (Neighbourhood::Moore(r) | Neighbourhood::VonNeumann(r)) = *self;
+		let r = r as i32;
+		let mut offsets = Vec::new();
+		for dx in -r..=r {
+			for dy in -r..=r {
+				let inside = match self {
+					Neighbourhood::Moore(_) => true,
+					Neighbourhood::VonNeumann(_) => dx.abs() + dy.abs() <= r,
+				};
+				if inside && (dx != 0 || dy != 0) {
+					offsets.push(IVec2::new(dx, dy));
+				}
+			}
+		}
+		offsets
+	}
+
+	pub fn size(&self) -> u32 {
+		self.offsets().len() as u32
+	}
+}
+
+pub trait CaRule: Send + Sync {
+	// Offsets of the cells whose walls are counted, not including the cell itself.
+	fn neighbourhood(&self) -> &[IVec2];
+	// Next state of a cell, given whether it is a wall and how many of its neighbours are walls.
+	fn next_state(&self, is_wall: bool, wall_neighbours: u32) -> bool;
+}
+
+// Open up sparse areas and fill in dense ones, leaving everything in between untouched.
+pub struct ThresholdRule {
+	pub open_threshold: u32,
+	pub wall_threshold: u32,
+	offsets: Vec<IVec2>,
+}
+
+impl ThresholdRule {
+	pub fn new(open_threshold: u32, wall_threshold: u32, neighbourhood: Neighbourhood) -> ThresholdRule {
+		ThresholdRule {
+			open_threshold: open_threshold,
+			wall_threshold: wall_threshold,
+			offsets: neighbourhood.offsets(),
+		}
+	}
+}
+
+impl CaRule for ThresholdRule {
+	fn neighbourhood(&self) -> &[IVec2] {
+		&self.offsets
+	}
+
+	fn next_state(&self, is_wall: bool, wall_neighbours: u32) -> bool {
+		if wall_neighbours <= self.open_threshold {
+			return false;
+		}
+		if wall_neighbours >= self.wall_threshold {
+			return true;
+		}
+		is_wall
+	}
+}
+
+// Life-like rule where walls are the live cells: an open cell turns into a wall when its
+// wall count is in `birth`, and a wall stays one when its count is in `survival`.
+pub struct LifeRule {
+	birth: Vec<bool>,
+	survival: Vec<bool>,
+	offsets: Vec<IVec2>,
+}
+
+impl LifeRule {
+	// Parses a rulestring such as "B678/S345678". Counts above 9 can be written as
+	// comma-separated numbers and ranges, e.g. "B34-45/S33-57" for larger neighbourhoods.
+	pub fn parse(rulestring: &str, neighbourhood: Neighbourhood) -> Result<LifeRule, String> {
+		let max_count = neighbourhood.size() as usize;
+		let mut birth = None;
+		let mut survival = None;
+		for part in rulestring.split('/') {
+			let part = part.trim();
+			let (target, counts) = match part.chars().next().map(|c| c.to_ascii_uppercase()) {
+				Some('B') => (&mut birth, &part[1..]),
+				Some('S') => (&mut survival, &part[1..]),
+				_ => return Err(format!("rulestring '{rulestring}' should look like B678/S345678")),
+			};
+			*target = Some(parse_counts(counts, max_count).map_err(|e| format!("rulestring '{rulestring}': {e}"))?);
+		}
+		match (birth, survival) {
+			(Some(birth), Some(survival)) => Ok(LifeRule {
+				birth: birth,
+				survival: survival,
+				offsets: neighbourhood.offsets(),
+			}),
+			_ => Err(format!("rulestring '{rulestring}' needs both a B and an S part")),
+		}
+	}
+}
+
+fn parse_counts(counts: &str, max_count: usize) -> Result<Vec<bool>, String> {
+	let mut set = vec![false; max_count + 1];
+	let parse = |s: &str| s.trim().parse::<usize>().map_err(|e| format!("{s}: {e}"));
+	let ranges = if counts.contains(',') || counts.contains('-') {
+		counts
+			.split(',')
+			.filter(|item| !item.trim().is_empty())
+			.map(|item| match item.split_once('-') {
+				Some((lo, hi)) => Ok((parse(lo)?, parse(hi)?)),
+				None => parse(item).map(|n| (n, n)),
+			})
+			.collect::<Result<Vec<_>, _>>()?
+	} else {
+		counts
+			.chars()
+			.map(|c| {
+				c.to_digit(10)
+					.map(|d| (d as usize, d as usize))
+					.ok_or(format!("{c} is not a digit"))
+			})
+			.collect::<Result<Vec<_>, _>>()?
+	};
+	for (lo, hi) in ranges {
+		if lo > hi || hi > max_count {
+			return Err(format!(
+				"count range {lo}-{hi} does not fit a neighbourhood of {max_count} cells"
+			));
+		}
+		set[lo..=hi].iter_mut().for_each(|b| *b = true);
+	}
+	Ok(set)
+}
+
+impl CaRule for LifeRule {
+	fn neighbourhood(&self) -> &[IVec2] {
+		&self.offsets
+	}
+
+	fn next_state(&self, is_wall: bool, wall_neighbours: u32) -> bool {
+		let counts = if is_wall { &self.survival } else { &self.birth };
+		counts.get(wall_neighbours as usize).copied().unwrap_or(false)
+	}
+}
+
+// Serializable description of a rule, as written in the simulator config.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CaRuleConfig {
+	Threshold {
+		open: u32,
+		wall: u32,
+		neighbourhood: Neighbourhood,
+	},
+	Life {
+		rule: String,
+		neighbourhood: Neighbourhood,
+	},
+}
+
+impl Default for CaRuleConfig {
+	fn default() -> Self {
+		CaRuleConfig::Threshold {
+			open: 3,
+			wall: 6,
+			neighbourhood: Neighbourhood::Moore(1),
+		}
+	}
+}
+
+impl CaRuleConfig {
+	pub fn build(&self) -> Result<Box<dyn CaRule>, String> {
+		let (CaRuleConfig::Threshold { neighbourhood, .. } | CaRuleConfig::Life { neighbourhood, .. }) = self;
+		if neighbourhood.size() == 0 {
+			return Err("neighbourhood radius must be positive".into());
+		}
+		match self {
+			CaRuleConfig::Threshold {
+				open,
+				wall,
+				neighbourhood,
+			} => {
+				if open >= wall || *wall > neighbourhood.size() {
+					return Err(format!(
+						"thresholds must satisfy open ({open}) < wall ({wall}) <= neighbourhood size ({})",
+						neighbourhood.size()
+					));
+				}
+				Ok(Box::new(ThresholdRule::new(*open, *wall, *neighbourhood)))
+			},
+			CaRuleConfig::Life { rule, neighbourhood } => Ok(Box::new(LifeRule::parse(rule, *neighbourhood)?)),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn counts(set: &[bool]) -> Vec<usize> {
+		set.iter().enumerate().filter(|(_, b)| **b).map(|(n, _)| n).collect()
+	}
+
+	#[test]
+	fn parse_life() {
+		let rule = LifeRule::parse("B3/S23", Neighbourhood::Moore(1)).unwrap();
+		assert_eq!(counts(&rule.birth), vec![3]);
+		assert_eq!(counts(&rule.survival), vec![2, 3]);
+		assert_eq!(rule.offsets.len(), 8);
+		assert!(rule.next_state(false, 3));
+		assert!(!rule.next_state(false, 2));
+		assert!(rule.next_state(true, 2));
+		assert!(!rule.next_state(true, 4));
+	}
+
+	#[test]
+	fn parse_cave() {
+		let rule = LifeRule::parse("B678/S345678", Neighbourhood::Moore(1)).unwrap();
+		assert_eq!(counts(&rule.birth), vec![6, 7, 8]);
+		assert_eq!(counts(&rule.survival), vec![3, 4, 5, 6, 7, 8]);
+		// Either order and lowercase letters are fine
+		let rule = LifeRule::parse("s345678/b678", Neighbourhood::Moore(1)).unwrap();
+		assert_eq!(counts(&rule.birth), vec![6, 7, 8]);
+		assert_eq!(counts(&rule.survival), vec![3, 4, 5, 6, 7, 8]);
+	}
+
+	#[test]
+	fn parse_ranges() {
+		assert_eq!(counts(&parse_counts("3-5,7", 24).unwrap()), vec![3, 4, 5, 7]);
+		assert_eq!(counts(&parse_counts("10, 12-13", 24).unwrap()), vec![10, 12, 13]);
+		assert_eq!(counts(&parse_counts("", 8).unwrap()), Vec::<usize>::new());
+	}
+
+	#[test]
+	fn parse_malformed() {
+		let moore = Neighbourhood::Moore(1);
+		assert!(LifeRule::parse("", moore).is_err());
+		assert!(LifeRule::parse("B3", moore).is_err());
+		assert!(LifeRule::parse("S23", moore).is_err());
+		assert!(LifeRule::parse("3/23", moore).is_err());
+		assert!(LifeRule::parse("B3/X23", moore).is_err());
+		assert!(LifeRule::parse("B3a/S23", moore).is_err());
+		// 9 neighbours do not fit in the Moore neighbourhood
+		assert!(LifeRule::parse("B9/S23", moore).is_err());
+		assert!(parse_counts("5-3", 8).is_err());
+		assert!(parse_counts("3-x", 8).is_err());
+		assert!(parse_counts("2,30", 24).is_err());
+	}
+}
@@ -16,7 +16,7 @@ use bevy::prelude::*;
 use goo::*;
 use rand::prelude::*;
 
-use crate::{player::*, tiles::*, tilesim::*, Despawn};
+use crate::{biomes::EnemyKind, player::*, tiles::*, tilesim::*, utils::WorldRng, Despawn};
 
 #[derive(Resource)]
 pub struct EnemySpawner {
@@ -24,6 +24,18 @@ pub struct EnemySpawner {
 	pub max_enemy_count: i64,
 }
 
+const BASE_MAX_ENEMY_COUNT: i64 = 4;
+const EXTRA_ENEMIES_PER_FLOOR: i64 = 2;
+
+impl EnemySpawner {
+	pub fn for_floor(floor: u32) -> EnemySpawner {
+		EnemySpawner {
+			timer: Timer::from_seconds(1.0, TimerMode::Repeating),
+			max_enemy_count: BASE_MAX_ENEMY_COUNT + EXTRA_ENEMIES_PER_FLOOR * floor.saturating_sub(1) as i64,
+		}
+	}
+}
+
 #[derive(Component)]
 pub struct Enemy;
 
@@ -34,6 +46,7 @@ pub fn spawn_random_enemy(
 	mut asset_server: Res<AssetServer>,
 	mut texture_atlases: ResMut<Assets<TextureAtlas>>,
 	mut spawner: ResMut<EnemySpawner>,
+	mut world_rng: ResMut<WorldRng>,
 	time: Res<Time>,
 	simulator: Res<Simulator>,
 	enemy_query: Query<&Enemy>,
@@ -42,9 +55,9 @@ pub fn spawn_random_enemy(
 	spawner.timer.tick(time.delta());
 
 	if spawner.timer.just_finished() && (enemy_query.iter().len() as i64) < spawner.max_enemy_count {
-		let mut rng = rand::thread_rng();
+		let rng = &mut world_rng.0;
 		let player_position = player_query.single().translation.truncate();
-		let spawn_distance = simulator.reality_params.0 as f32 * TILE_SIZE as f32;
+		let spawn_distance = simulator.config.reality_inner_radius as f32 * TILE_SIZE as f32;
 		let angle = rng.gen_range(0.0..TAU);
 
 		let displacement = Vec2::new(angle.cos(), angle.sin()) * spawn_distance as f32;
@@ -53,20 +66,45 @@ pub fn spawn_random_enemy(
 		let pos = position_to_tile_position(&spawn_position);
 
 		// Enemies failing to spawn sometimes adds randomness and is fine
-		if simulator.in_bounds(pos) && simulator.grid.is_wall[pos.x as usize][pos.y as usize] {
+		if simulator.grid.kind(pos).map_or(false, TileKind::blocks_walking) {
 			return;
 		}
 
-		let rand_z: f32 = rng.gen();
-		let position = spawn_position.extend(1.0 + rand_z);
-		match rng.gen_range(0..3) {
-			0 => spawn_ranger(&mut commands, &mut asset_server, &mut texture_atlases, position),
-			1 => spawn_wraith(&mut commands, &mut asset_server, position),
-			_ => spawn_goo(&mut commands, &mut asset_server, position),
-		}
+		spawn_biome_enemy(
+			&mut commands,
+			&mut asset_server,
+			&mut texture_atlases,
+			rng,
+			&simulator,
+			spawn_position,
+		);
 	}
 }
 
+// Spawn an enemy from the spawn table of the biome at the given position, if it has one.
+pub fn spawn_biome_enemy<R: Rng>(
+	commands: &mut Commands,
+	asset_server: &mut Res<AssetServer>,
+	texture_atlases: &mut ResMut<Assets<TextureAtlas>>,
+	rng: &mut R,
+	simulator: &Simulator,
+	spawn_position: Vec2,
+) {
+	// Each biome has its own spawn table
+	let pos = position_to_tile_position(&spawn_position);
+	let kind = match simulator.biome(pos).enemies.choose_weighted(rng, |(_, weight)| *weight) {
+		Ok((kind, _)) => *kind,
+		Err(_) => return,
+	};
+	let rand_z: f32 = rng.gen();
+	let position = spawn_position.extend(1.0 + rand_z);
+	match kind {
+		EnemyKind::Ranger => spawn_ranger(commands, asset_server, texture_atlases, position),
+		EnemyKind::Wraith => spawn_wraith(commands, asset_server, position),
+		EnemyKind::Goo => spawn_goo(commands, asset_server, position),
+	};
+}
+
 pub fn despawn_far_enemies(
 	mut commands: Commands,
 	simulator: Res<Simulator>,
@@ -74,7 +112,7 @@ pub fn despawn_far_enemies(
 	player_query: Query<&Transform, With<Player>>,
 ) {
 	let player_position = player_query.single().translation.truncate();
-	let despawn_distance = simulator.reality_params.1 as f32 * TILE_SIZE as f32;
+	let despawn_distance = simulator.config.reality_outer_radius as f32 * TILE_SIZE as f32;
 
 	for (entity, transform) in enemy_query.iter() {
 		let position = transform.translation.truncate();
@@ -4,7 +4,9 @@ use bevy::{math::Vec3Swizzles, prelude::*};
 use bevy_rapier2d::prelude::*;
 use rand::Rng;
 
-use crate::{gems::DropsGems, mob::*, player::*, tiles::position_to_tile_position, utils::*, Simulator};
+use crate::{
+	gems::DropsGems, mob::*, player::*, tiles::position_to_tile_position, tilesim::TerrainChanged, utils::*, Simulator,
+};
 
 pub enum BossState {
 	Waiting(f32),
@@ -24,7 +26,7 @@ const BOSS_CHARGE_TO_CIRCLE: f32 = 3.0;
 const CIRCLE_ATTACK_TICK: f32 = 0.5;
 const CIRCLE_ATTACK_TICKS: u32 = 20;
 
-pub fn spawn_boss(mut commands: &mut Commands, asset_server: &Res<AssetServer>, pos: Vec2) {
+pub fn spawn_boss(mut commands: &mut Commands, asset_server: &Res<AssetServer>, pos: Vec2) -> Entity {
 	commands
 		.spawn(SpriteBundle {
 			texture: asset_server.load("spider.png"),
@@ -44,7 +46,7 @@ pub fn spawn_boss(mut commands: &mut Commands, asset_server: &Res<AssetServer>,
 		})
 		.insert(RigidBody::Fixed)
 		.insert(LockedAxes::ROTATION_LOCKED)
-		.insert(CollidesWithWalls)
+		.insert(CollidesWithWalls { crush_damage: 0 })
 		.insert(Collider::cuboid(24.0, 24.0))
 		.insert(Dominance::group(10))
 		.insert(PlayerDanger {
@@ -52,7 +54,8 @@ pub fn spawn_boss(mut commands: &mut Commands, asset_server: &Res<AssetServer>,
 			hit_despawn: false,
 			til_despawn: f32::INFINITY,
 		})
-		.insert(DropsGems(15, 15));
+		.insert(DropsGems(15, 15))
+		.id()
 }
 
 pub fn boss_shoot(commands: &mut Commands, asset_server: &Res<AssetServer>, source: Vec2, angle: f32) {
@@ -80,17 +83,20 @@ pub fn run_boss(
 	mut commands: Commands,
 	asset_server: Res<AssetServer>,
 	mut simulator: ResMut<Simulator>,
+	mut world_rng: ResMut<WorldRng>,
 	players: Query<&Transform, With<Player>>,
 	mut bosses: Query<(&mut Transform, &mut Velocity, &mut EnemyBoss), Without<Player>>,
+	mut terrain_changes: EventWriter<TerrainChanged>,
 ) {
 	let player_pos: Vec2 = players.single().translation.xy();
+	let rng = &mut world_rng.0;
 	for (mut transform, mut velocity, mut boss) in bosses.iter_mut() {
 		boss.state = match boss.state {
 			BossState::Waiting(ticks) => {
 				let t = ticks - time.delta().as_secs_f32();
 				if t < 0.0 {
-					let dist_at = rand::thread_rng().gen_range::<f32, _>(100.0..300.0);
-					let angle_at = rand::thread_rng().gen_range::<f32, _>(0.0..TAU);
+					let dist_at = rng.gen_range::<f32, _>(100.0..300.0);
+					let angle_at = rng.gen_range::<f32, _>(0.0..TAU);
 					let new_pos = player_pos + Vec2::from_angle(angle_at) * dist_at;
 					BossState::Teleporting(new_pos, BOSS_TELEPORT_FRAME, 0)
 				} else {
@@ -100,20 +106,10 @@ pub fn run_boss(
 			BossState::Teleporting(new_pos, ticks, step) => {
 				let t = ticks - time.delta().as_secs_f32();
 				if t > 0.0 {
-					let center_tile = position_to_tile_position(&new_pos);
-					for dx in -6..=6 {
-						for dy in -6..=6 {
-							let x = (center_tile.x as i32 + dx) as usize;
-							let y = (center_tile.y as i32 + dy) as usize;
-							if dx.abs() + dy.abs() <= step && x < MAP_RADIUS_USIZE * 2 && y < MAP_RADIUS_USIZE * 2 {
-								simulator.grid.is_wall[x][y] = false;
-							}
-						}
-					}
+					simulator.clear_diamond(position_to_tile_position(&new_pos), step);
 					if step >= 7 {
 						transform.translation = new_pos.extend(2.0);
 						velocity.linvel = Vec2::ZERO;
-						let mut rng = rand::thread_rng();
 						for _ in 0..5 {
 							boss_shoot(&mut commands, &asset_server, new_pos, rng.gen_range(0.0..TAU));
 						}
@@ -170,4 +166,5 @@ pub fn run_boss(
 			},
 		}
 	}
+	terrain_changes.send_batch(simulator.drain_terrain_changes());
 }
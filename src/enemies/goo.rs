@@ -14,8 +14,8 @@ pub struct EnemyGoo {
 	state: EnemyGooState,
 }
 
-pub fn spawn_goo(commands: &mut Commands, asset_server: &mut Res<AssetServer>, position: Vec3) {
-	commands.spawn((
+pub fn spawn_goo(commands: &mut Commands, asset_server: &mut Res<AssetServer>, position: Vec3) -> Entity {
+	let goo = commands.spawn((
 		SpriteBundle {
 			texture: asset_server.load("slime.png"),
 			transform: Transform::from_translation(position),
@@ -34,7 +34,7 @@ pub fn spawn_goo(commands: &mut Commands, asset_server: &mut Res<AssetServer>, p
 		},
 		RigidBody::Dynamic,
 		LockedAxes::ROTATION_LOCKED,
-		CollidesWithWalls,
+		CollidesWithWalls { crush_damage: 5 },
 		Collider::cuboid(12.0, 12.0),
 		Enemy,
 		SpriteFacingMovement,
@@ -45,6 +45,7 @@ pub fn spawn_goo(commands: &mut Commands, asset_server: &mut Res<AssetServer>, p
 		},
 		DropsGems(1, 2),
 	));
+	goo.id()
 }
 
 pub fn run_goo(
@@ -43,12 +43,12 @@ pub fn spawn_ranger(
 	asset_server: &mut Res<AssetServer>,
 	texture_atlases: &mut ResMut<Assets<TextureAtlas>>,
 	position: Vec3,
-) {
+) -> Entity {
 	let texture_handle = asset_server.load("ranger.png");
 	let texture_atlas = TextureAtlas::from_grid(texture_handle, Vec2::new(32.0, 32.0), 10, 10, None, None);
 	let texture_atlas_handle = texture_atlases.add(texture_atlas);
 
-	commands.spawn((
+	let ranger = commands.spawn((
 		SpriteSheetBundle {
 			texture_atlas: texture_atlas_handle,
 			transform: Transform::from_translation(position),
@@ -66,7 +66,7 @@ pub fn spawn_ranger(
 		},
 		RigidBody::Dynamic,
 		LockedAxes::ROTATION_LOCKED,
-		CollidesWithWalls,
+		CollidesWithWalls { crush_damage: 10 },
 		Collider::cuboid(12.0, 12.0),
 		Enemy,
 		SpriteFacingMovement,
@@ -77,6 +77,7 @@ pub fn spawn_ranger(
 		},
 		DropsGems(2, 2),
 	));
+	ranger.id()
 }
 
 pub fn run_ranger(
@@ -13,8 +13,8 @@ pub struct EnemyWraith {
 	angle_vel: f32,
 }
 
-pub fn spawn_wraith(commands: &mut Commands, asset_server: &mut Res<AssetServer>, position: Vec3) {
-	commands.spawn((
+pub fn spawn_wraith(commands: &mut Commands, asset_server: &mut Res<AssetServer>, position: Vec3) -> Entity {
+	let wraith = commands.spawn((
 		SpriteBundle {
 			texture: asset_server.load("wraith.png"),
 			transform: Transform::from_translation(position),
@@ -43,6 +43,7 @@ pub fn spawn_wraith(commands: &mut Commands, asset_server: &mut Res<AssetServer>
 		},
 		DropsGems(3, 3),
 	));
+	wraith.id()
 }
 
 pub fn run_wraith(
@@ -0,0 +1,100 @@
+use bevy::{math::Vec3Swizzles, prelude::*};
+use rand::Rng;
+
+use crate::{
+	enemies::{boss::EnemyBoss, EnemySpawner},
+	gems::Gem,
+	mob::*,
+	player::Player,
+	shooting::Projectile,
+	simconfig::SimulatorConfig,
+	tiles::*,
+	tilesim::Simulator,
+	utils::*,
+	Despawn,
+};
+
+// How deep the player is, starting at floor 1.
+#[derive(Resource)]
+pub struct Floor(pub u32);
+
+impl Default for Floor {
+	fn default() -> Self {
+		Floor(1)
+	}
+}
+
+// Opens where the boss died and takes the player down to the next floor.
+#[derive(Component)]
+pub struct DescentPortal;
+
+pub fn open_descent_portal(mut commands: Commands, bosses: Query<(&Transform, &Mob), With<EnemyBoss>>) {
+	for (transform, mob) in bosses.iter() {
+		if mob.health <= 0 {
+			commands.spawn((
+				SpriteBundle {
+					transform: Transform::from_translation(transform.translation.xy().extend(0.7)),
+					sprite: Sprite {
+						color: Color::rgba(0.6, 0.3, 1., 0.8),
+						custom_size: Some(Vec2::splat(TILE_SIZE * 1.5)),
+						..default()
+					},
+					..default()
+				},
+				Bounded {
+					size: Vec2::splat(TILE_SIZE),
+				},
+				DescentPortal,
+			));
+		}
+	}
+}
+
+// Build the next floor from a fresh seed and harder parameters. The player keeps their level and gems.
+pub fn take_descent_portal(
+	mut commands: Commands,
+	config: Res<SimulatorConfig>,
+	mut simulator: ResMut<Simulator>,
+	mut floor: ResMut<Floor>,
+	mut spawner: ResMut<EnemySpawner>,
+	mut seed: ResMut<WorldSeed>,
+	mut world_rng: ResMut<WorldRng>,
+	portals: Query<(&Transform, &Bounded), With<DescentPortal>>,
+	mut players: Query<(&mut Transform, &Bounded), (With<Player>, Without<DescentPortal>)>,
+	leftovers: Query<
+		Entity,
+		Or<(
+			With<DescentPortal>,
+			With<Mob>,
+			With<PlayerDanger>,
+			With<Projectile>,
+			With<Gem>,
+			With<Tile>,
+			With<BackTile>,
+			With<Structure>,
+		)>,
+	>,
+) {
+	let (mut player_transform, player_bound) = players.single_mut();
+	let player_rect = Rect::from_center_size(player_transform.translation.xy(), player_bound.size);
+	let entered = portals.iter().any(|(transform, bound)| {
+		let portal_rect = Rect::from_center_size(transform.translation.xy(), bound.size);
+		!portal_rect.intersect(player_rect).is_empty()
+	});
+	if !entered {
+		return;
+	}
+
+	floor.0 += 1;
+	*seed = WorldSeed(world_rng.0.gen());
+	println!("Descending to floor {}, world seed: {}", floor.0, seed.0);
+	*world_rng = WorldRng::new(*seed);
+	*simulator = Simulator::new(config.for_floor(floor.0), *seed);
+	simulator.post_init();
+	*spawner = EnemySpawner::for_floor(floor.0);
+
+	for entity in leftovers.iter() {
+		commands.entity(entity).insert(Despawn);
+	}
+	player_transform.translation = _tile_position_to_position(&simulator.world_center()).extend(2.);
+}
@@ -0,0 +1,112 @@
+use bevy::prelude::*;
+
+// Turns the first octant into each of the eight octants around the origin: (xx, xy, yx, yy).
+const OCTANTS: [(i32, i32, i32, i32); 8] = [
+	(1, 0, 0, 1),
+	(0, 1, 1, 0),
+	(0, -1, 1, 0),
+	(-1, 0, 0, 1),
+	(-1, 0, 0, -1),
+	(0, -1, -1, 0),
+	(0, 1, -1, 0),
+	(1, 0, 0, -1),
+];
+
+// Calls `visit` on every cell that can be seen from `origin` within `radius`, using recursive shadowcasting.
+// Opaque cells are visited themselves, so the faces of walls get seen, but hide whatever is behind them.
+// Cells on the edges between octants may be visited more than once.
+pub fn shadowcast(origin: IVec2, radius: i32, is_opaque: impl Fn(IVec2) -> bool, mut visit: impl FnMut(IVec2)) {
+	visit(origin);
+	for (xx, xy, yx, yy) in OCTANTS {
+		let to_world = |dx: i32, dy: i32| origin + IVec2::new(dx * xx + dy * xy, dx * yx + dy * yy);
+		cast_light(radius, 1, 1., 0., &to_world, &is_opaque, &mut visit);
+	}
+}
+
+// Scans the rows of one octant from `row` outwards, between the slopes `start` and `end`, with `to_world`
+// mapping octant coordinates to tiles. Recurses into the part of the next row that is still lit
+// whenever a row gets blocked.
+fn cast_light(
+	radius: i32,
+	row: i32,
+	mut start: f32,
+	end: f32,
+	to_world: &impl Fn(i32, i32) -> IVec2,
+	is_opaque: &impl Fn(IVec2) -> bool,
+	visit: &mut impl FnMut(IVec2),
+) {
+	if start < end {
+		return;
+	}
+	let mut new_start = 0.;
+	for j in row..=radius {
+		let dy = -j;
+		let mut blocked = false;
+		for dx in -j..=0 {
+			let loc = to_world(dx, dy);
+			let (dx, dy) = (dx as f32, dy as f32);
+			let left_slope = (dx - 0.5) / (dy + 0.5);
+			let right_slope = (dx + 0.5) / (dy - 0.5);
+			if start < right_slope {
+				continue;
+			} else if end > left_slope {
+				break;
+			}
+			if dx * dx + dy * dy <= (radius * radius) as f32 {
+				visit(loc);
+			}
+			let opaque = is_opaque(loc);
+			if blocked {
+				if opaque {
+					new_start = right_slope;
+				} else {
+					blocked = false;
+					start = new_start;
+				}
+			} else if opaque && j < radius {
+				blocked = true;
+				cast_light(radius, j + 1, start, left_slope, to_world, is_opaque, visit);
+				new_start = right_slope;
+			}
+		}
+		if blocked {
+			return;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bevy::utils::HashSet;
+
+	use super::*;
+
+	// Cells seen from the origin with a three tile wall standing across the x axis at x = 3.
+	fn seen(radius: i32) -> HashSet<IVec2> {
+		let mut seen = HashSet::default();
+		let is_wall = |loc: IVec2| loc.x == 3 && loc.y.abs() <= 1;
+		shadowcast(IVec2::ZERO, radius, is_wall, |loc| {
+			seen.insert(loc);
+		});
+		return seen;
+	}
+
+	#[test]
+	fn walls_are_seen_but_hide_what_is_behind_them() {
+		let seen = seen(6);
+		assert!(seen.contains(&IVec2::new(2, 0)));
+		assert!(seen.contains(&IVec2::new(3, 0)));
+		assert!(!seen.contains(&IVec2::new(4, 0)));
+		assert!(!seen.contains(&IVec2::new(5, 0)));
+		// Past the end of the wall is in plain view
+		assert!(seen.contains(&IVec2::new(4, 3)));
+	}
+
+	#[test]
+	fn nothing_beyond_the_radius_is_seen() {
+		let seen = seen(6);
+		assert!(seen.contains(&IVec2::new(0, 6)));
+		assert!(seen.contains(&IVec2::new(-6, 0)));
+		assert!(seen.iter().all(|loc| loc.x * loc.x + loc.y * loc.y <= 36));
+	}
+}
@@ -12,8 +12,13 @@ pub struct Gem;
 #[derive(Component)]
 pub struct DropsGems(pub i64, pub i64);
 
-pub fn spawn_gems(commands: &mut Commands, asset_server: &mut Res<AssetServer>, count: i64, position: Vec2) {
-	let mut rng = rand::thread_rng();
+pub fn spawn_gems<R: Rng>(
+	commands: &mut Commands,
+	asset_server: &mut Res<AssetServer>,
+	rng: &mut R,
+	count: i64,
+	position: Vec2,
+) {
 	let rand_z: f32 = rng.gen_range(1.0..2.0);
 
 	for _ in 0..count {
@@ -0,0 +1,151 @@
+use bevy::{prelude::*, utils::StableHashMap};
+use serde::{Deserialize, Serialize};
+
+// Side length of the square chunks the world is stored in.
+pub const CHUNK_SIZE: u32 = 16;
+pub const CHUNK_AREA: usize = (CHUNK_SIZE * CHUNK_SIZE) as usize;
+
+// Coordinate of the chunk containing a tile. Chunk coordinates are signed, so the world can extend
+// in every direction from the origin.
+pub fn chunk_of(loc: IVec2) -> IVec2 {
+	let size = CHUNK_SIZE as i32;
+	IVec2::new(loc.x.div_euclid(size), loc.y.div_euclid(size))
+}
+
+// Tile at the bottom-left corner of a chunk.
+pub fn chunk_origin(chunk: IVec2) -> IVec2 {
+	chunk * CHUNK_SIZE as i32
+}
+
+// Index of a tile within the cell arrays of its chunk.
+pub fn cell_index(loc: IVec2) -> usize {
+	let size = CHUNK_SIZE as i32;
+	(loc.y.rem_euclid(size) * size + loc.x.rem_euclid(size)) as usize
+}
+
+// All tiles of a chunk, column by column.
+pub fn chunk_positions(chunk: IVec2) -> impl Iterator<Item = IVec2> {
+	let origin = chunk_origin(chunk);
+	let size = CHUNK_SIZE as i32;
+	(0..size).flat_map(move |x| (0..size).map(move |y| origin + IVec2::new(x, y)))
+}
+
+// Chunks overlapping the square of tiles between `min` and `max`, both inclusive.
+pub fn chunks_between(min: IVec2, max: IVec2) -> impl Iterator<Item = IVec2> {
+	let (min, max) = (chunk_of(min), chunk_of(max));
+	(min.x..=max.x).flat_map(move |x| (min.y..=max.y).map(move |y| IVec2::new(x, y)))
+}
+
+// A grid of cells, stored in CHUNK_SIZE x CHUNK_SIZE chunks.
+// A bounded grid covers the tiles of its extent from the start. An unbounded one extends in every direction
+// and only holds the chunks that have been loaded, which can be dropped again.
+// All accessors are bounds-checked, so reading or writing outside of the grid cannot go wrong.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Grid<T> {
+	// Corners of the tiles in the grid, both inclusive, or None if it has no edge
+	bounds: Option<(IVec2, IVec2)>,
+	chunks: StableHashMap<IVec2, Vec<T>>,
+}
+
+impl<T: Clone> Grid<T> {
+	// A width x height grid with its bottom-left corner at the origin.
+	pub fn new(width: u32, height: u32, fill: T) -> Grid<T> {
+		let max = IVec2::new(width as i32 - 1, height as i32 - 1);
+		let mut grid = Grid {
+			bounds: Some((IVec2::ZERO, max)),
+			chunks: default(),
+		};
+		for chunk in chunks_between(IVec2::ZERO, max) {
+			grid.chunks.insert(chunk, vec![fill.clone(); CHUNK_AREA]);
+		}
+		grid
+	}
+
+	pub fn unbounded() -> Grid<T> {
+		Grid {
+			bounds: None,
+			chunks: default(),
+		}
+	}
+
+	pub fn fill(&mut self, value: T) {
+		for chunk in self.chunks.values_mut() {
+			chunk.fill(value.clone());
+		}
+	}
+
+	// Returns whether the chunk was missing from an unbounded grid and got loaded.
+	pub fn load_chunk(&mut self, chunk: IVec2, fill: T) -> bool {
+		if self.bounds.is_some() || self.chunks.contains_key(&chunk) {
+			return false;
+		}
+		self.chunks.insert(chunk, vec![fill; CHUNK_AREA]);
+		true
+	}
+}
+
+impl<T> Grid<T> {
+	pub fn is_bounded(&self) -> bool {
+		self.bounds.is_some()
+	}
+
+	// Corners of the area the grid currently covers, both inclusive, or None if nothing is loaded.
+	pub fn extent(&self) -> Option<(IVec2, IVec2)> {
+		if self.bounds.is_some() || self.chunks.is_empty() {
+			return self.bounds;
+		}
+		let min = self.chunks.keys().fold(IVec2::splat(i32::MAX), |a, c| a.min(*c));
+		let max = self.chunks.keys().fold(IVec2::splat(i32::MIN), |a, c| a.max(*c));
+		Some((chunk_origin(min), chunk_origin(max) + IVec2::splat(CHUNK_SIZE as i32 - 1)))
+	}
+
+	pub fn is_loaded(&self, chunk: IVec2) -> bool {
+		self.chunks.contains_key(&chunk)
+	}
+
+	pub fn in_bounds(&self, loc: IVec2) -> bool {
+		match self.bounds {
+			Some((min, max)) => loc.cmpge(min).all() && loc.cmple(max).all(),
+			None => self.is_loaded(chunk_of(loc)),
+		}
+	}
+
+	pub fn get(&self, loc: IVec2) -> Option<&T> {
+		if !self.in_bounds(loc) {
+			return None;
+		}
+		self.chunks.get(&chunk_of(loc)).map(|chunk| &chunk[cell_index(loc)])
+	}
+
+	pub fn get_mut(&mut self, loc: IVec2) -> Option<&mut T> {
+		if !self.in_bounds(loc) {
+			return None;
+		}
+		self.chunks.get_mut(&chunk_of(loc)).map(|chunk| &mut chunk[cell_index(loc)])
+	}
+
+	// Returns whether the cell was in bounds and got written.
+	pub fn set(&mut self, loc: IVec2, value: T) -> bool {
+		self.get_mut(loc).map(|cell| *cell = value).is_some()
+	}
+
+	// Coordinates of the loaded chunks.
+	pub fn chunks(&self) -> impl Iterator<Item = IVec2> + '_ {
+		self.chunks.keys().copied()
+	}
+
+	// Drop the chunks of an unbounded grid that `keep` turns down.
+	pub fn retain_chunks(&mut self, mut keep: impl FnMut(IVec2) -> bool) {
+		if self.bounds.is_none() {
+			self.chunks.retain(|chunk, _| keep(*chunk));
+		}
+	}
+
+	// All positions in the grid, column by column within each chunk.
+	pub fn positions(&self) -> impl Iterator<Item = IVec2> + '_ {
+		self.chunks
+			.keys()
+			.flat_map(|chunk| chunk_positions(*chunk))
+			.filter(|loc| self.in_bounds(*loc))
+	}
+}
@@ -2,11 +2,15 @@ use bevy::{prelude::*, render::render_resource::TextureFormat};
 use image::{DynamicImage, ImageBuffer, Rgba};
 
 use crate::{
+	floors::Floor,
 	gems::Gem,
-	player::{CrystalCooldownTimer, FireboltCooldownTimer, MineCooldownTimer, Player, PlayerWeaponSelect, MAX_HEALTH},
+	player::{
+		CrystalCooldownTimer, FireboltCooldownTimer, MineCooldownTimer, Player, PlayerWeaponSelect, MAX_HEALTH,
+		STARTING_TORCHES,
+	},
 	tiles::position_to_tile_position,
-	tilesim::Simulator,
-	utils::{DEBUG_OMNISCIENCE, MAP_RADIUS, MINIMAP_SIZE},
+	tilesim::{Simulator, TileKind},
+	utils::{DEBUG_OMNISCIENCE, MINIMAP_SIZE},
 };
 
 pub struct MinimapPlugin;
@@ -19,7 +23,9 @@ impl Plugin for MinimapPlugin {
 			.add_system(update_player_health_indicators)
 			.add_system(update_spell_cooldown_overlays)
 			.add_system(update_spell_indicator)
-			.add_system(update_gem_count);
+			.add_system(update_gem_count)
+			.add_system(update_torch_count)
+			.add_system(update_floor_count);
 	}
 }
 
@@ -39,6 +45,12 @@ struct SpellIndicator;
 #[derive(Component)]
 struct GemCount;
 
+#[derive(Component)]
+struct FloorCount;
+
+#[derive(Component)]
+struct TorchCount;
+
 #[derive(Component)]
 struct SpellCooldownOverlay(PlayerWeaponSelect);
 
@@ -47,9 +59,11 @@ fn setup_total_minimap(
 	mut commands: Commands,
 	mut assets: ResMut<Assets<Image>>,
 	mut total_minimap: ResMut<TotalMinimap>,
+	simulator: Res<Simulator>,
 ) {
+	let width = simulator.config.width;
 	total_minimap.handle = assets.add(Image::from_dynamic(
-		DynamicImage::ImageRgba8(ImageBuffer::new(MAP_RADIUS * 2, MAP_RADIUS * 2)),
+		DynamicImage::ImageRgba8(ImageBuffer::new(width, width)),
 		true,
 	));
 	commands.spawn(ImageBundle {
@@ -133,6 +147,52 @@ fn setup_total_minimap(
 		)
 		.insert(GemCount);
 
+	// Torch counter right of the gems
+	commands
+		.spawn(
+			TextBundle::from_section(
+				format!("Torches {STARTING_TORCHES}"),
+				TextStyle {
+					font: asset_server.load("fonts/DejaVuSans.ttf"),
+					font_size: 16.0,
+					color: Color::WHITE,
+				},
+			)
+			.with_style(Style {
+				position_type: PositionType::Absolute,
+				position: UiRect {
+					left: Val::Px(120.0),
+					bottom: Val::Px(16.0),
+					..default()
+				},
+				..default()
+			}),
+		)
+		.insert(TorchCount);
+
+	// Floor counter under the hearts
+	commands
+		.spawn(
+			TextBundle::from_section(
+				"Floor 1",
+				TextStyle {
+					font: asset_server.load("fonts/DejaVuSans.ttf"),
+					font_size: 16.0,
+					color: Color::WHITE,
+				},
+			)
+			.with_style(Style {
+				position_type: PositionType::Absolute,
+				position: UiRect {
+					left: Val::Px(10.0),
+					top: Val::Px(80.0),
+					..default()
+				},
+				..default()
+			}),
+		)
+		.insert(FloorCount);
+
 	// Add current spell indicator on the bottom-right
 	commands
 		.spawn(NodeBundle {
@@ -223,6 +283,30 @@ fn update_gem_count(asset_server: Res<AssetServer>, players: Query<&Player>, mut
 	}
 }
 
+fn update_torch_count(players: Query<&Player>, mut indicators: Query<&mut Text, With<TorchCount>>) {
+	for player in players.iter() {
+		for mut text in indicators.iter_mut() {
+			text.sections[0].value = format!("Torches {}", player.torches);
+		}
+	}
+}
+
+fn update_floor_count(asset_server: Res<AssetServer>, floor: Res<Floor>, mut indicators: Query<&mut Text, With<FloorCount>>) {
+	if !floor.is_changed() {
+		return;
+	}
+	for mut text in indicators.iter_mut() {
+		text.sections = vec![TextSection::new(
+			format!("Floor {}", floor.0),
+			TextStyle {
+				font: asset_server.load("fonts/DejaVuSans.ttf"),
+				font_size: 16.0,
+				color: Color::WHITE,
+			},
+		)];
+	}
+}
+
 fn update_player_health_indicators(players: Query<&Player>, mut indicators: Query<(&mut Visibility, &PlayerHealthIndicator)>) {
 	let mut player_health = 0;
 
@@ -303,15 +387,24 @@ fn update_total_minimap(
 ) {
 	if let Some(image) = assets.get_mut(&total_minimap.handle) {
 		let player_position = player_query.single().translation.truncate();
-		let mut image_buffer = ImageBuffer::new(MAP_RADIUS * 2, MAP_RADIUS * 2);
+		let player_tile = position_to_tile_position(&player_position);
+		let width = simulator.config.width;
+		// An infinite world is shown in a width x width window that follows the player
+		let corner = if simulator.config.infinite {
+			player_tile - IVec2::splat(width as i32 / 2)
+		} else {
+			IVec2::ZERO
+		};
+		let mut image_buffer = ImageBuffer::new(width, width);
 		for (x, y, p) in image_buffer.enumerate_pixels_mut() {
-			if DEBUG_OMNISCIENCE || simulator.grid.reality_bubble.contains(&UVec2::new(x, MAP_RADIUS * 2 - y - 1)) {
-				*p = get_minimap_color(
-					&simulator,
-					position_to_tile_position(&player_position),
-					x,
-					MAP_RADIUS * 2 - y - 1,
-				);
+			let pos = corner + IVec2::new(x as i32, (width - y - 1) as i32);
+			if DEBUG_OMNISCIENCE {
+				*p = get_minimap_color(&simulator, player_tile, pos);
+			} else if simulator.grid.reality_bubble.contains(&pos) {
+				// Fade out cells as they are forgotten, but never below the forgotten ones
+				let mut colour = get_minimap_color(&simulator, player_tile, pos);
+				colour[3] = (colour[3] as f32 * simulator.grid.memory(pos)).max(25.) as u8;
+				*p = colour;
 			} else {
 				*p = Rgba([102, 102, 255, 25]);
 			}
@@ -320,15 +413,19 @@ fn update_total_minimap(
 	}
 }
 
-fn get_minimap_color(simulator: &Simulator, player_pos: UVec2, i: u32, j: u32) -> Rgba<u8> {
-	let pos = UVec2::new(i, j);
+fn get_minimap_color(simulator: &Simulator, player_pos: IVec2, pos: IVec2) -> Rgba<u8> {
 	if pos.as_vec2().distance(player_pos.as_vec2()) < 2. {
 		Rgba([255, 255, 255, 255])
-	} else if simulator.grid.campfires.contains(&pos) {
+	} else if simulator.grid.has_campfire(pos) {
 		Rgba([102, 255, 102, 127])
-	} else if simulator.grid.is_wall[i as usize][j as usize] {
-		Rgba([102, 102, 255, 51])
 	} else {
-		Rgba([102, 102, 255, 127])
+		match simulator.grid.tile_kind(pos) {
+			TileKind::Wall => Rgba([102, 102, 255, 51]),
+			TileKind::Floor => Rgba([102, 102, 255, 127]),
+			TileKind::Water => Rgba([51, 153, 255, 127]),
+			TileKind::Chasm => Rgba([0, 0, 0, 127]),
+			TileKind::CrystalVein => Rgba([102, 230, 255, 127]),
+			TileKind::Rubble => Rgba([153, 140, 128, 127]),
+		}
 	}
 }
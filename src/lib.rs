@@ -0,0 +1,12 @@
+// Everything needed to generate and step the maze, without Bevy's windowing or rendering.
+// The game binary and the tenebris-sim tool are both built on top of this.
+pub mod biomes;
+pub mod carule;
+pub mod fov;
+pub mod grid;
+pub mod mapdump;
+pub mod simconfig;
+pub mod structures;
+pub mod tilemap;
+pub mod tilesim;
+pub mod utils;
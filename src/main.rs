@@ -5,6 +5,8 @@ use bevy::{
 };
 use bevy_prototype_debug_lines::*;
 use bevy_rapier2d::prelude::*;
+use rand::Rng;
+use tenebris::{biomes, simconfig, structures, tilemap, tilesim, utils};
 
 mod camera;
 use camera::*;
@@ -30,18 +32,22 @@ use hud::*;
 mod mob;
 use mob::*;
 
-mod tilemap;
+mod gems;
+use gems::*;
 
-mod tilesim;
-use tilesim::*;
+mod floors;
+use floors::*;
 
-mod utils;
-use utils::*;
+mod save;
+use save::*;
 
-mod gems;
-use gems::*;
+mod replay;
+use replay::*;
 
-mod structures;
+use simconfig::*;
+use structures::StructureType;
+use tilesim::*;
+use utils::*;
 
 #[derive(Component)]
 pub struct Despawn;
@@ -51,6 +57,16 @@ pub const SCREEN_DIMENSIONS: (f32, f32) = (1024.0, 768.0);
 pub const DESPAWN_STAGE: &str = "DESPAWN";
 const TIME_STEP: f32 = 1.0 / 60.0;
 
+// Systems that steer the player and the mobs by setting their velocity.
+#[derive(SystemLabel)]
+pub struct Movement;
+
+// Systems that draw from the WorldRng or change the Simulator. They run one after the other in a fixed order,
+// since the order bevy picks by itself can change from one run to the next, and a replay would drift away from
+// its recording.
+#[derive(SystemLabel)]
+pub struct WorldUpdate;
+
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum AppState {
 	Alive,
@@ -58,16 +74,20 @@ pub enum AppState {
 }
 
 fn main() {
+	let mut seed = WorldSeed::from_env_or_entropy();
+	let replay = Replay::from_env(seed);
+	if let Some(replay_seed) = replay.seed() {
+		seed = replay_seed;
+	}
+	println!("World seed: {}", seed.0);
+	let config = SimulatorConfig::load(SIMULATOR_CONFIG_PATH).unwrap_or_else(|e| panic!("{e}"));
 	App::new()
 		.add_state(AppState::Alive)
 		.insert_resource(ClearColor(Color::rgb_u8(1, 0, 0)))
 		.insert_resource(Atlases::default())
 		.insert_resource(Msaa { samples: 1 })
 		.insert_resource(SimulatorTimer(Timer::from_seconds(0.1, TimerMode::Repeating)))
-		.insert_resource(EnemySpawner {
-			timer: Timer::from_seconds(1.0, TimerMode::Repeating),
-			max_enemy_count: 4,
-		})
+		.insert_resource(EnemySpawner::for_floor(1))
 		.add_plugins(
 			DefaultPlugins
 				.set(AssetPlugin {
@@ -95,67 +115,82 @@ fn main() {
 		)
 		.add_system_set(SystemSet::on_update(AppState::Alive).with_system(check_if_dead))
 		.add_system_set(SystemSet::on_update(AppState::Dead).with_system(enter_dead))
-		.add_system_set(SystemSet::on_enter(AppState::Alive).with_system(reset_vars))
+		.add_system_set(SystemSet::on_enter(AppState::Alive).with_system(reset_vars.before(WorldUpdate)))
 		.add_plugin(RapierPhysicsPlugin::<NoUserData>::pixels_per_meter(32.))
 		.add_plugin(DebugLinesPlugin::default())
 		.add_plugin(LogDiagnosticsPlugin::default())
 		.add_plugin(FrameTimeDiagnosticsPlugin::default())
 		.add_plugin(EntityCountDiagnosticsPlugin::default())
-		.insert_resource(Simulator::new(
-			MAP_RADIUS * 2,
-			(3, 6),
-			(10, MAP_RADIUS - 6),
-			(10, 13),
-			15,
-			(20, 30),
-			10,
-			10,
-			20,
-			5,
-			20,
-		))
+		.insert_resource(seed)
+		.insert_resource(WorldRng::new(seed))
+		.insert_resource(Simulator::new(config.clone(), seed))
+		.insert_resource(config)
+		.insert_resource(Floor::default())
+		.init_resource::<Darkness>()
+		.add_event::<TerrainChanged>()
+		.add_event::<CellForgotten>()
+		.add_event::<Detonation>()
+		.insert_resource(PendingSave::from_disk(&replay))
+		.insert_resource(replay)
+		.add_plugin(ReplayPlugin)
 		.insert_resource(SimulatorTimer(Timer::from_seconds(0.1, TimerMode::Repeating)))
 		.insert_resource(Atlases::default())
 		.insert_resource(Msaa { samples: 1 })
-		.insert_resource(EnemySpawner {
-			timer: Timer::from_seconds(1.0, TimerMode::Repeating),
-			max_enemy_count: 4,
-		})
+		.insert_resource(EnemySpawner::for_floor(1))
 		.add_plugin(MinimapPlugin)
 		// Startup Systems
 		.add_startup_system(setup)
 		.add_startup_system(setup_player)
-		.add_system(player_shoot)
+		.add_startup_system(setup_darkness)
+		.add_startup_system(offer_continue)
 		// Enemies
 		// .add_startup_system(spawn_boss)
 		.add_system(update_level_using_gems)
-		.add_system(spawn_random_enemy)
 		.add_system(danger_hit_player)
 		.add_system(tick_down_player_invincibility)
-		.add_system(unspawn_dead_mobs)
-		.add_system(run_wraith)
-		.add_system(run_goo)
-		.add_system(run_boss)
-		.add_system(run_ranger)
+		.add_system(run_wraith.label(Movement))
+		.add_system(run_goo.label(Movement))
+		.add_system(run_ranger.label(Movement))
 		.add_system(animate_ranger_sprite)
-		.add_system(projectile_hit_mobs)
 		.add_system(mob_face_movement_sprite_sheet)
 		.add_system(mob_face_movement_sprite)
-		.add_system(despawn_far_enemies)
+		.add_system(despawn_far_enemies.after(WorldUpdate))
+		// The boss only has no health left between the hit that kills it and its despawn
+		.add_system(open_descent_portal.after(projectile_hit_mobs).before(unspawn_dead_mobs))
 		// Player, Projectiles
-		.add_system(update_velocity)
+		.add_system(update_velocity.label(Movement))
 		.add_system(update_select)
 		.add_system(animate_player_sprite)
 		.add_system(update_cooldowns)
 		.add_system(update_crystals_velocity)
 		.add_system(update_mines_velocity)
-		.add_system(despawn_old_projectiles)
 		// Tiles
-		.add_system(spawn_tiles)
-		.add_system(despawn_tiles)
-		.add_system(update_tiles)
-		.add_system(simulator_step)
-		.add_system(update_lightmap)
+		.add_system(push_out_of_walls.after(WorldUpdate))
+		.add_system(wade_through_water.after(Movement).after(WorldUpdate))
+		.add_system(update_campfire_sprites)
+		.add_system_set(
+			SystemSet::new()
+				.label(WorldUpdate)
+				.with_system(continue_saved_run)
+				.with_system(take_descent_portal.after(continue_saved_run))
+				.with_system(spawn_random_enemy.after(take_descent_portal))
+				.with_system(run_boss.label(Movement).after(spawn_random_enemy))
+				.with_system(player_shoot.after(run_boss))
+				// Projectiles go off before the walls they blast get broken
+				.with_system(projectile_hit_mobs.after(player_shoot))
+				.with_system(unspawn_dead_mobs.after(projectile_hit_mobs))
+				.with_system(despawn_old_projectiles.after(unspawn_dead_mobs))
+				.with_system(projectiles_break_crystal_veins.after(despawn_old_projectiles))
+				.with_system(detonations_break_walls.after(projectiles_break_crystal_veins))
+				.with_system(spawn_tiles.after(detonations_break_walls))
+				// After spawn_tiles, which would draw campfires placed in slots a second time
+				.with_system(fill_prefab_slots.after(spawn_tiles))
+				.with_system(despawn_tiles.after(fill_prefab_slots))
+				.with_system(update_tiles.after(despawn_tiles))
+				.with_system(simulator_step.after(update_tiles))
+				.with_system(update_lightmap.after(simulator_step))
+				.with_system(autosave_on_exit.after(update_lightmap)),
+		)
 		.add_stage_after(CoreStage::Update, DESPAWN_STAGE, SystemStage::single_threaded())
 		.add_system_to_stage(DESPAWN_STAGE, despawn)
 		.add_system_to_stage(CoreStage::PostUpdate, update_camera)
@@ -223,6 +258,13 @@ pub fn reset_vars(
 	mut commands: Commands,
 	asset_server: Res<AssetServer>,
 	mut simulator: ResMut<Simulator>,
+	config: Res<SimulatorConfig>,
+	mut seed: ResMut<WorldSeed>,
+	mut world_rng: ResMut<WorldRng>,
+	mut floor: ResMut<Floor>,
+	mut spawner: ResMut<EnemySpawner>,
+	mut restarted: Local<bool>,
+	portals: Query<Entity, With<DescentPortal>>,
 	mut set: ParamSet<(
 		Query<(&mut Transform, &mut Player)>,
 		Query<(Entity, &EnemyBoss)>,
@@ -231,30 +273,30 @@ pub fn reset_vars(
 	)>,
 ) {
 	println!("Restting vars");
+	if *restarted {
+		// Derive the next world from the previous one, so that a whole session replays from the initial seed
+		*seed = WorldSeed(world_rng.0.gen());
+		println!("World seed: {}", seed.0);
+	}
+	*restarted = true;
+	*world_rng = WorldRng::new(*seed);
+	// Dying sends the player back up to the first floor
+	*floor = Floor::default();
+	*spawner = EnemySpawner::for_floor(floor.0);
+	*simulator = Simulator::new(config.for_floor(floor.0), *seed);
+	simulator.post_init();
 	for (mut transform, mut player) in set.p0().iter_mut() {
-		*transform = Transform::from_translation(Vec3::new(32. * MAP_RADIUS as f32, 32. * MAP_RADIUS as f32, 2.));
+		*transform = Transform::from_translation(_tile_position_to_position(&simulator.world_center()).extend(2.));
 		*player = Player {
 			level: 0,
 			health: MAX_HEALTH,
 			invincibility_seconds: 2.0,
 			gem_count: 0,
+			torches: STARTING_TORCHES,
+			torch_fuel: Vec::new(),
 			select: PlayerWeaponSelect::Firebolt,
 		};
 	}
-	*simulator = Simulator::new(
-		MAP_RADIUS * 2,
-		(3, 6),
-		(10, MAP_RADIUS - 6),
-		(10, 13),
-		15,
-		(20, 30),
-		2,
-		10,
-		20,
-		5,
-		20,
-	);
-	simulator.post_init();
 	for (boss, _) in set.p1().iter() {
 		commands.entity(boss).insert(Despawn);
 	}
@@ -265,45 +307,75 @@ pub fn reset_vars(
 	for (projectile, _) in set.p3().iter() {
 		commands.entity(projectile).insert(Despawn);
 	}
+	for portal in portals.iter() {
+		commands.entity(portal).insert(Despawn);
+	}
 }
 
+// Gems it costs to fill a campfire back up.
+const CAMPFIRE_REFUEL_COST: i32 = 3;
+// Gems it costs to craft a torch at an altar.
+const TORCH_COST: i32 = 5;
+
 pub fn simulator_step(
 	mut commands: Commands,
 	mut simulator: ResMut<Simulator>,
-	player: Query<&Transform, With<Player>>,
-	structures: Query<(Entity, &Transform), With<Structure>>,
+	mut player: Query<(&Transform, &mut Player)>,
 	mut timer: ResMut<SimulatorTimer>,
 	time: Res<Time>,
 	keyboard_input: Res<Input<KeyCode>>,
 	atlases: Res<Atlases>,
 	asset_server: Res<AssetServer>,
+	mut terrain_changes: EventWriter<TerrainChanged>,
+	mut forgotten_cells: EventWriter<CellForgotten>,
 ) {
-	let player_trans = player.single().translation.truncate();
+	let (player_transform, mut player) = player.single_mut();
+	let player_trans = player_transform.translation.truncate();
 	let player_pos = position_to_tile_position(&player_trans);
 	timer.0.tick(time.delta());
 	if keyboard_input.just_pressed(KeyCode::E) {
 		// Spawn boss if close to boss spawner
 		let boss_room_loc = simulator.boss_room_loc();
 		if boss_room_loc.as_vec2().distance(player_pos.as_vec2()) < 5. {
-			spawn_boss(&mut commands, &asset_server, _tile_position_to_position(&boss_room_loc))
-		} else if simulator.grid.campfires.contains(&player_pos) {
-			simulator.remove_campfire(player_pos);
-			for (e, t) in structures.iter() {
-				let structure_trans = t.translation.truncate();
-				if position_to_tile_position(&structure_trans) == player_pos {
-					commands.entity(e).insert(Despawn);
-				}
+			spawn_boss(&mut commands, &asset_server, _tile_position_to_position(&boss_room_loc));
+		} else if simulator.grid.has_campfire(player_pos) {
+			// Pick the torch back up with whatever fuel it has left. Its sprite goes away with it in
+			// update_campfire_sprites. Campfires the player did not light stay where they are.
+			if let Some(fuel) = simulator.pick_up_torch(player_pos) {
+				player.torches += 1;
+				player.torch_fuel.push(fuel);
 			}
-		} else {
-			simulator.place_campfire(player_pos);
+		} else if near_altar(&simulator, player_pos) {
+			if player.gem_count >= TORCH_COST {
+				player.gem_count -= TORCH_COST;
+				player.torches += 1;
+			}
+		} else if player.torches > 0 {
+			let fuel = player.torch_fuel.pop().unwrap_or(simulator.config.campfire_fuel);
+			simulator.place_torch(player_pos, fuel);
 			spawn_campfire_sprite(&mut commands, &atlases, player_pos);
+			player.torches -= 1;
+		}
+	}
+	if keyboard_input.just_pressed(KeyCode::R) && player.gem_count >= CAMPFIRE_REFUEL_COST {
+		if simulator.refuel_campfire(player_pos) {
+			player.gem_count -= CAMPFIRE_REFUEL_COST;
 		}
 	}
 	if timer.0.just_finished() {
 		simulator.step(player_pos);
+		terrain_changes.send_batch(simulator.drain_terrain_changes());
+		forgotten_cells.send_batch(simulator.drain_forgotten_cells());
 	}
 }
 
+// Torches are crafted at altars.
+fn near_altar(simulator: &Simulator, player_pos: IVec2) -> bool {
+	simulator.grid.structures_near(player_pos, 2).any(|loc| {
+		matches!(simulator.grid.structure(loc), Some(StructureType::Altar)) && loc.as_vec2().distance(player_pos.as_vec2()) < 2.
+	})
+}
+
 pub fn despawn(mut commands: Commands, despawns: Query<Entity, With<Despawn>>) {
 	for entity in despawns.iter() {
 		commands.entity(entity).despawn();
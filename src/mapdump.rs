@@ -0,0 +1,145 @@
+use std::{fmt, fs, path::Path, str::FromStr};
+
+use bevy::prelude::IVec2;
+use image::{imageops, Rgba, RgbaImage};
+
+use crate::tilesim::{Simulator, TileKind};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DumpFormat {
+	Ascii,
+	Png,
+}
+
+impl DumpFormat {
+	pub fn extension(&self) -> &'static str {
+		match self {
+			DumpFormat::Ascii => "txt",
+			DumpFormat::Png => "png",
+		}
+	}
+}
+
+impl FromStr for DumpFormat {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"ascii" | "txt" => Ok(DumpFormat::Ascii),
+			"png" => Ok(DumpFormat::Png),
+			_ => Err(format!("unknown format '{s}', expected 'ascii' or 'png'")),
+		}
+	}
+}
+
+impl fmt::Display for DumpFormat {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			DumpFormat::Ascii => write!(f, "ascii"),
+			DumpFormat::Png => write!(f, "png"),
+		}
+	}
+}
+
+// Legend:
+// @ player, * campfire, $ structure, # wall, . floor, ~ water, _ chasm, % crystal vein, : rubble.
+// Cells outside of the reality bubble use lowercase-ish variants: + for walls and , for floor.
+pub fn render_ascii(simulator: &Simulator, player_pos: IVec2) -> String {
+	let (min, max) = simulator.extent();
+	let size = max - min + IVec2::ONE;
+	let mut out = String::with_capacity(((size.x + 1) * size.y) as usize);
+	// Rows go from the top of the world (high y) to the bottom, like on screen.
+	for y in (min.y..=max.y).rev() {
+		for x in min.x..=max.x {
+			let pos = IVec2::new(x, y);
+			let remembered = simulator.grid.reality_bubble.contains(&pos);
+			out.push(if pos == player_pos {
+				'@'
+			} else if simulator.grid.has_campfire(pos) {
+				'*'
+			} else if simulator.grid.structure(pos).is_some() {
+				'$'
+			} else {
+				match simulator.grid.tile_kind(pos) {
+					TileKind::Wall if remembered => '#',
+					TileKind::Wall => '+',
+					TileKind::Floor if remembered => '.',
+					TileKind::Floor => ',',
+					TileKind::Water => '~',
+					TileKind::Chasm => '_',
+					TileKind::CrystalVein => '%',
+					TileKind::Rubble => ':',
+				}
+			});
+		}
+		out.push('\n');
+	}
+	out
+}
+
+// Remembered and forgotten floor colours of each biome, repeating if there are more biomes than colours.
+const BIOME_FLOOR_COLOURS: [([u8; 3], [u8; 3]); 3] = [
+	([102, 102, 255], [61, 61, 128]),
+	([102, 204, 140], [61, 122, 84]),
+	([170, 170, 190], [102, 102, 114]),
+];
+
+// Blend from the forgotten colour to the remembered one as memory goes from 0 to 1.
+fn fade(forgotten: [u8; 3], remembered: [u8; 3], memory: f32) -> [u8; 3] {
+	let mut out = forgotten;
+	for i in 0..3 {
+		out[i] = (forgotten[i] as f32 + (remembered[i] as f32 - forgotten[i] as f32) * memory).round() as u8;
+	}
+	out
+}
+
+// Same palette as the minimap, but opaque, with forgotten cells still visible and floors coloured by biome.
+// Remembered cells fade towards the forgotten colours as their memory decays.
+pub fn render_image(simulator: &Simulator, player_pos: IVec2, scale: u32) -> RgbaImage {
+	let (min, max) = simulator.extent();
+	let size = (max - min + IVec2::ONE).as_uvec2();
+	let image = RgbaImage::from_fn(size.x, size.y, |x, y| {
+		let pos = IVec2::new(min.x + x as i32, max.y - y as i32);
+		let memory = simulator.grid.memory(pos);
+		let (floor, forgotten_floor) = BIOME_FLOOR_COLOURS[simulator.grid.biome(pos) % BIOME_FLOOR_COLOURS.len()];
+		if pos == player_pos {
+			Rgba([255, 255, 255, 255])
+		} else if simulator.grid.has_campfire(pos) {
+			Rgba([102, 255, 102, 255])
+		} else if simulator.grid.structure(pos).is_some() {
+			Rgba([255, 204, 51, 255])
+		} else {
+			let [r, g, b] = match simulator.grid.tile_kind(pos) {
+				TileKind::Wall => fade([20, 20, 31], [31, 31, 77], memory),
+				TileKind::Floor => fade(forgotten_floor, floor, memory),
+				TileKind::Water => fade([31, 51, 92], [51, 102, 204], memory),
+				TileKind::Chasm => fade([8, 8, 10], [5, 5, 5], memory),
+				TileKind::CrystalVein => fade([41, 82, 92], [77, 204, 230], memory),
+				TileKind::Rubble => fade([77, 71, 66], [140, 128, 115], memory),
+			};
+			Rgba([r, g, b, 255])
+		}
+	});
+	if scale > 1 {
+		imageops::resize(&image, size.x * scale, size.y * scale, imageops::FilterType::Nearest)
+	} else {
+		image
+	}
+}
+
+pub fn write_frame(
+	simulator: &Simulator,
+	player_pos: IVec2,
+	format: DumpFormat,
+	scale: u32,
+	path: &Path,
+) -> Result<(), String> {
+	match format {
+		DumpFormat::Ascii => {
+			fs::write(path, render_ascii(simulator, player_pos)).map_err(|e| format!("{}: {e}", path.display()))
+		},
+		DumpFormat::Png => render_image(simulator, player_pos, scale)
+			.save(path)
+			.map_err(|e| format!("{}: {e}", path.display())),
+	}
+}
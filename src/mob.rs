@@ -1,11 +1,12 @@
 use bevy::{math::Vec3Swizzles, prelude::*};
 use bevy_rapier2d::prelude::Velocity;
-use rand::{thread_rng, Rng};
+use rand::Rng;
 
 use crate::{
 	gems::{spawn_gems, DropsGems},
 	player::*,
-	shooting::Projectile,
+	shooting::{Blast, Detonation, Projectile},
+	utils::WorldRng,
 	Despawn,
 };
 
@@ -17,7 +18,10 @@ pub struct Bounded {
 }
 
 #[derive(Component)]
-pub struct CollidesWithWalls;
+pub struct CollidesWithWalls {
+	// Damage taken when a wall regrows on top of it
+	pub crush_damage: i32,
+}
 
 #[derive(Component)]
 pub struct Mob {
@@ -33,16 +37,23 @@ pub struct PlayerDanger {
 
 pub fn projectile_hit_mobs(
 	mut commands: Commands,
-	mut projectiles: Query<(Entity, &Transform, &Bounded, &Projectile), Without<Despawn>>,
+	mut detonations: EventWriter<Detonation>,
+	mut projectiles: Query<(Entity, &Transform, &Bounded, &Projectile, Option<&Blast>), Without<Despawn>>,
 	mut mobs: Query<(&Transform, &Bounded, &mut Mob)>,
 ) {
-	for (proj_entity, proj_transform, proj_bound, proj) in projectiles.iter_mut() {
+	for (proj_entity, proj_transform, proj_bound, proj, blast) in projectiles.iter_mut() {
 		let proj_rect = Rect::from_center_size(proj_transform.translation.xy(), proj_bound.size);
 		for (mob_transform, mob_bound, mut mob) in mobs.iter_mut() {
 			let mob_rect = Rect::from_center_size(mob_transform.translation.xy(), mob_bound.size);
 			if !proj_rect.intersect(mob_rect).is_empty() {
 				mob.health -= proj.damage;
 				commands.entity(proj_entity).insert(Despawn);
+				if let Some(blast) = blast {
+					detonations.send(Detonation {
+						position: proj_transform.translation.xy(),
+						radius: blast.radius,
+					});
+				}
 				break;
 			}
 		}
@@ -103,15 +114,21 @@ pub fn mob_face_movement_sprite(mut mob_query: Query<(&mut Sprite, &Velocity), W
 pub fn unspawn_dead_mobs(
 	mut commands: Commands,
 	mut asset_server: Res<AssetServer>,
+	mut world_rng: ResMut<WorldRng>,
 	mobs: Query<(Entity, &Transform, &Mob, &DropsGems), Without<Player>>,
 ) {
 	for (entity, transform, mob, gem_dist) in mobs.iter() {
-		let mut rng = thread_rng();
-		let gem_count = gem_dist.0 + rng.gen_range(0..gem_dist.1);
-
 		if mob.health <= 0 {
+			let rng = &mut world_rng.0;
+			let gem_count = gem_dist.0 + rng.gen_range(0..gem_dist.1);
 			commands.entity(entity).insert(Despawn);
-			spawn_gems(&mut commands, &mut asset_server, gem_count, transform.translation.truncate())
+			spawn_gems(
+				&mut commands,
+				&mut asset_server,
+				rng,
+				gem_count,
+				transform.translation.truncate(),
+			)
 		}
 	}
 }
@@ -3,7 +3,7 @@ use std::time::Duration;
 use bevy::prelude::*;
 use bevy_rapier2d::prelude::*;
 
-use crate::{mob::*, shooting::*, utils::MAP_RADIUS};
+use crate::{mob::*, shooting::*, tiles::_tile_position_to_position, tilesim::Simulator};
 
 #[derive(Clone)]
 pub enum PlayerWeaponSelect {
@@ -12,12 +12,17 @@ pub enum PlayerWeaponSelect {
 	Mine,
 }
 pub const MAX_HEALTH: i32 = 60;
+// Torches the player carries when starting out.
+pub const STARTING_TORCHES: u32 = 3;
 
 #[derive(Component)]
 pub struct Player {
 	pub health: i32,
 	pub invincibility_seconds: f32,
 	pub gem_count: i32,
+	pub torches: u32,
+	// Fuel left in the torches picked back up from campfires, which get lit again first. Any other torches are unburnt.
+	pub torch_fuel: Vec<u32>,
 	pub select: PlayerWeaponSelect,
 	pub level: u32,
 }
@@ -205,7 +210,12 @@ pub fn animate_player_sprite(
 	}
 }
 
-pub fn setup_player(mut commands: Commands, asset_server: Res<AssetServer>, mut texture_atlases: ResMut<Assets<TextureAtlas>>) {
+pub fn setup_player(
+	mut commands: Commands,
+	asset_server: Res<AssetServer>,
+	mut texture_atlases: ResMut<Assets<TextureAtlas>>,
+	simulator: Res<Simulator>,
+) {
 	let texture_handle = asset_server.load("wizard.png");
 	let texture_atlas = TextureAtlas::from_grid(texture_handle, Vec2::new(32.0, 32.0), 10, 10, None, None);
 	let texture_atlas_handle = texture_atlases.add(texture_atlas);
@@ -215,6 +225,8 @@ pub fn setup_player(mut commands: Commands, asset_server: Res<AssetServer>, mut
 			health: MAX_HEALTH,
 			invincibility_seconds: 2.0,
 			gem_count: 0,
+			torches: STARTING_TORCHES,
+			torch_fuel: Vec::new(),
 			select: PlayerWeaponSelect::Firebolt,
 			level: 0,
 		},
@@ -225,14 +237,14 @@ pub fn setup_player(mut commands: Commands, asset_server: Res<AssetServer>, mut
 		},
 		SpriteSheetBundle {
 			texture_atlas: texture_atlas_handle,
-			transform: Transform::from_translation(Vec3::new(32. * MAP_RADIUS as f32, 32. * MAP_RADIUS as f32, 2.)),
+			transform: Transform::from_translation(_tile_position_to_position(&simulator.world_center()).extend(2.)),
 			..default()
 		},
 		AnimationTimer(Timer::from_seconds(0.05, TimerMode::Repeating)),
 		Bounded {
 			size: Vec2::new(32., 32.),
 		},
-		CollidesWithWalls,
+		CollidesWithWalls { crush_damage: 10 },
 		FireboltCooldownTimer(Timer::from_seconds(FIREBALL_COOLDOWN, TimerMode::Once)),
 		CrystalCooldownTimer(Timer::from_seconds(CRYSTAL_COOLDOWN, TimerMode::Once)),
 		MineCooldownTimer(Timer::from_seconds(MINE_COOLDOWN, TimerMode::Once)),
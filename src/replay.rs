@@ -0,0 +1,338 @@
+use std::{fs, time::Duration};
+
+use bevy::{app::AppExit, input::InputSystem, prelude::*, window::WindowCloseRequested};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::*;
+
+// Set one of these environment variables to a file path to record a run into it, or to play one back.
+pub const RECORD_ENV_VAR: &str = "TENEBRIS_RECORD";
+pub const REPLAY_ENV_VAR: &str = "TENEBRIS_REPLAY";
+// Bump whenever ReplayFile changes shape.
+const REPLAY_VERSION: u32 = 2;
+
+// Every key some gameplay system reads. A bit in a KeyMask stands for the key at the same index.
+const RECORDED_KEYS: [KeyCode; 11] = [
+	KeyCode::W,
+	KeyCode::A,
+	KeyCode::S,
+	KeyCode::D,
+	KeyCode::Key1,
+	KeyCode::Key2,
+	KeyCode::Key3,
+	KeyCode::E,
+	KeyCode::R,
+	KeyCode::C,
+	KeyCode::N,
+];
+const RECORDED_BUTTONS: [MouseButton; 3] = [MouseButton::Left, MouseButton::Right, MouseButton::Middle];
+
+// Where the mouse points in the world, if it is on the window at all.
+// Gameplay reads the aim from here rather than the window, so that a replay can set it. It is kept in world
+// space, so that a replay aims at the same spot whatever the size of the window it is played back in.
+#[derive(Default, Resource)]
+pub struct Aim(pub Option<Vec2>);
+
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+struct KeyMask {
+	pressed: u32,
+	just_pressed: u32,
+	just_released: u32,
+}
+
+impl KeyMask {
+	fn read<T: Copy + Eq + std::hash::Hash + Send + Sync + 'static>(input: &Input<T>, keys: &[T]) -> KeyMask {
+		let mut mask = KeyMask::default();
+		for (i, &key) in keys.iter().enumerate() {
+			mask.pressed |= (input.pressed(key) as u32) << i;
+			mask.just_pressed |= (input.just_pressed(key) as u32) << i;
+			mask.just_released |= (input.just_released(key) as u32) << i;
+		}
+		return mask;
+	}
+
+	// Puts `input` in exactly the recorded state, dropping whatever is actually being pressed.
+	fn write<T: Copy + Eq + std::hash::Hash + Send + Sync + 'static>(&self, input: &mut Input<T>, keys: &[T]) {
+		input.reset_all();
+		for (i, &key) in keys.iter().enumerate() {
+			if self.just_released & (1 << i) != 0 {
+				input.press(key);
+				input.release(key);
+			}
+			if self.pressed & (1 << i) != 0 {
+				input.press(key);
+			}
+			if self.just_pressed & (1 << i) == 0 {
+				input.clear_just_pressed(key);
+			}
+		}
+	}
+}
+
+// Everything the game reads from the player during one frame.
+#[derive(Serialize, Deserialize)]
+struct InputFrame {
+	delta: Duration,
+	keys: KeyMask,
+	buttons: KeyMask,
+	aim: Option<Vec2>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReplayFile {
+	version: u32,
+	seed: WorldSeed,
+	frames: Vec<InputFrame>,
+}
+
+impl ReplayFile {
+	fn load(path: &str) -> Result<ReplayFile, String> {
+		let text = fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+		let replay: ReplayFile = ron::from_str(&text).map_err(|e| format!("{path}: {e}"))?;
+		if replay.version != REPLAY_VERSION {
+			return Err(format!(
+				"{path}: replay version {} is not supported, expected {REPLAY_VERSION}",
+				replay.version
+			));
+		}
+		return Ok(replay);
+	}
+
+	fn write(&self, path: &str) -> Result<(), String> {
+		let text = ron::to_string(self).map_err(|e| e.to_string())?;
+		fs::write(path, text).map_err(|e| format!("{path}: {e}"))
+	}
+}
+
+#[derive(Resource)]
+pub enum Replay {
+	Off,
+	Recording { path: String, file: ReplayFile },
+	Playing { file: ReplayFile, next: usize },
+	// A replay ran out and the player took over from where it left off
+	Finished,
+}
+
+impl Replay {
+	// Picks the mode from the environment. The world seed is taken from the replay when playing one back,
+	// and is otherwise the one given.
+	pub fn from_env(seed: WorldSeed) -> Replay {
+		if let Ok(path) = std::env::var(REPLAY_ENV_VAR) {
+			let file = ReplayFile::load(&path).unwrap_or_else(|e| panic!("{e}"));
+			println!("Replaying {} frames from {path}", file.frames.len());
+			return Replay::Playing { file: file, next: 0 };
+		}
+		if let Ok(path) = std::env::var(RECORD_ENV_VAR) {
+			println!("Recording the run to {path}");
+			return Replay::Recording {
+				path: path,
+				file: ReplayFile {
+					version: REPLAY_VERSION,
+					seed: seed,
+					frames: Vec::new(),
+				},
+			};
+		}
+		return Replay::Off;
+	}
+
+	pub fn seed(&self) -> Option<WorldSeed> {
+		match self {
+			Replay::Playing { file, .. } => Some(file.seed),
+			_ => None,
+		}
+	}
+
+	// Whether a run got recorded or replayed at any point of this session.
+	pub fn is_on(&self) -> bool {
+		!matches!(self, Replay::Off)
+	}
+}
+
+// Runs right after bevy reads the devices. Records this frame's input, or overwrites it with the recorded one,
+// frame time included, so that every system downstream sees the same thing it did during the recording.
+pub fn record_or_replay_input(
+	mut replay: ResMut<Replay>,
+	mut aim: ResMut<Aim>,
+	mut time: ResMut<Time>,
+	mut keyboard_input: ResMut<Input<KeyCode>>,
+	mut mouse_button_input: ResMut<Input<MouseButton>>,
+	windows: Res<Windows>,
+	cameras: Query<(&Camera, &GlobalTransform)>,
+) {
+	aim.0 = match (windows.get_primary(), cameras.get_single()) {
+		(Some(window), Ok((camera, camera_transform))) => window
+			.cursor_position()
+			.map(|cursor_position| cursor_world_position(camera, camera_transform, window, cursor_position)),
+		_ => None,
+	};
+	match replay.as_mut() {
+		Replay::Off | Replay::Finished => {},
+		Replay::Recording { file, .. } => {
+			file.frames.push(InputFrame {
+				delta: time.delta(),
+				keys: KeyMask::read(&keyboard_input, &RECORDED_KEYS),
+				buttons: KeyMask::read(&mouse_button_input, &RECORDED_BUTTONS),
+				aim: aim.0,
+			});
+		},
+		Replay::Playing { file, next } => {
+			if let Some(frame) = file.frames.get(*next) {
+				*next += 1;
+				if let Some(last_update) = time.last_update() {
+					time.update_with_instant(last_update + frame.delta);
+				}
+				frame.keys.write(&mut keyboard_input, &RECORDED_KEYS);
+				frame.buttons.write(&mut mouse_button_input, &RECORDED_BUTTONS);
+				aim.0 = frame.aim;
+				return;
+			}
+			// Hand the run over to the player
+			println!("Replay finished");
+			*replay = Replay::Finished;
+		},
+	}
+}
+
+fn cursor_world_position(camera: &Camera, camera_transform: &GlobalTransform, window: &Window, cursor_position: Vec2) -> Vec2 {
+	let window_size = Vec2::new(window.width() as f32, window.height() as f32);
+
+	// convert screen position [0..resolution] to ndc [-1..1] (gpu coordinates)
+	let ndc = (cursor_position / window_size) * 2.0 - Vec2::ONE;
+
+	// matrix for undoing the projection and camera transform
+	let ndc_to_world = camera_transform.compute_matrix() * camera.projection_matrix().inverse();
+
+	// use it to convert ndc to world-space coordinates
+	let world_pos = ndc_to_world.project_point3(ndc.extend(-1.0));
+
+	// reduce it to a 2D value
+	return world_pos.truncate();
+}
+
+// Write the recording out when the window gets closed.
+pub fn save_recording_on_exit(
+	mut close_requests: EventReader<WindowCloseRequested>,
+	mut exits: EventReader<AppExit>,
+	mut saved: Local<bool>,
+	replay: Res<Replay>,
+) {
+	let quitting = close_requests.iter().count() > 0 || exits.iter().count() > 0;
+	if !quitting || *saved {
+		return;
+	}
+	*saved = true;
+	if let Replay::Recording { path, file } = replay.as_ref() {
+		match file.write(path) {
+			Ok(()) => println!("Saved {} recorded frames to {path}", file.frames.len()),
+			Err(e) => println!("Could not save the recording: {e}"),
+		}
+	}
+}
+
+pub struct ReplayPlugin;
+
+impl Plugin for ReplayPlugin {
+	fn build(&self, app: &mut App) {
+		app.init_resource::<Aim>()
+			.add_system_to_stage(CoreStage::PreUpdate, record_or_replay_input.after(InputSystem))
+			.add_system(save_recording_on_exit);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bevy::{asset::AssetPlugin, input::InputPlugin};
+
+	use super::*;
+	use crate::{
+		assets::Atlases,
+		player::{Player, PlayerWeaponSelect, MAX_HEALTH, STARTING_TORCHES},
+		simconfig::SimulatorConfig,
+		simulator_step,
+		tiles::_tile_position_to_position,
+		tilesim::*,
+	};
+
+	// A couple of seconds of uneven frames, lighting a torch and picking it back up.
+	fn recording() -> ReplayFile {
+		let e = 1 << RECORDED_KEYS.iter().position(|&key| key == KeyCode::E).unwrap();
+		let frames = (0..150)
+			.map(|i| InputFrame {
+				delta: Duration::from_millis(10 + i % 7),
+				keys: KeyMask {
+					pressed: if i == 20 || i == 110 { e } else { 0 },
+					just_pressed: if i == 20 || i == 110 { e } else { 0 },
+					just_released: if i == 21 || i == 111 { e } else { 0 },
+				},
+				buttons: KeyMask::default(),
+				aim: None,
+			})
+			.collect();
+		return ReplayFile {
+			version: REPLAY_VERSION,
+			seed: WorldSeed(7),
+			frames: frames,
+		};
+	}
+
+	// Play a recording back without a window, and capture the world and the player's torches at the end.
+	fn play(file: ReplayFile) -> (String, u32, Vec<u32>) {
+		let n_frames = file.frames.len();
+		let mut simulator = Simulator::new(SimulatorConfig::default(), file.seed);
+		simulator.post_init();
+		let start_tile = simulator.world_center();
+		let start = _tile_position_to_position(&start_tile);
+		let mut app = App::new();
+		app.add_plugins(MinimalPlugins)
+			.add_plugin(AssetPlugin::default())
+			.add_plugin(InputPlugin)
+			.init_resource::<Windows>()
+			.add_event::<WindowCloseRequested>()
+			.add_event::<TerrainChanged>()
+			.add_event::<CellForgotten>()
+			.init_resource::<Atlases>()
+			.insert_resource(simulator)
+			.insert_resource(SimulatorTimer(Timer::from_seconds(0.1, TimerMode::Repeating)))
+			.insert_resource(Replay::Playing { file: file, next: 0 })
+			.add_plugin(ReplayPlugin)
+			.add_system(simulator_step);
+		app.world.spawn((
+			Transform::from_translation(start.extend(2.)),
+			Player {
+				health: MAX_HEALTH,
+				invincibility_seconds: 2.0,
+				gem_count: 0,
+				torches: STARTING_TORCHES,
+				torch_fuel: Vec::new(),
+				select: PlayerWeaponSelect::Firebolt,
+				level: 0,
+			},
+		));
+		for frame in 0..n_frames {
+			app.update();
+			// Make sure the recording actually did something
+			if frame == 20 {
+				assert!(
+					app.world.resource::<Simulator>().grid.has_campfire(start_tile),
+					"no torch was lit"
+				);
+			} else if frame == 110 {
+				let player = app.world.query::<&Player>().single(&app.world);
+				assert_eq!(player.torches, STARTING_TORCHES, "the torch was not picked back up");
+				assert_eq!(player.torch_fuel.len(), 1);
+			}
+		}
+		let player = app.world.query::<&Player>().single(&app.world);
+		let (torches, torch_fuel) = (player.torches, player.torch_fuel.clone());
+		let state = app.world.resource_mut::<Simulator>().snapshot();
+		return (ron::to_string(&state).unwrap(), torches, torch_fuel);
+	}
+
+	#[test]
+	fn replays_match() {
+		let (first, second) = (play(recording()), play(recording()));
+		assert!(first.0 == second.0, "the worlds differ");
+		assert_eq!((first.1, &first.2), (second.1, &second.2));
+	}
+}
@@ -0,0 +1,288 @@
+use std::{fs, path::Path};
+
+use bevy::{app::AppExit, prelude::*, window::WindowCloseRequested};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	biomes::EnemyKind,
+	enemies::{boss::*, goo::*, ranger::*, wraith::*, EnemySpawner},
+	floors::*,
+	gems::Gem,
+	mob::*,
+	player::Player,
+	replay::Replay,
+	shooting::Projectile,
+	tiles::*,
+	tilesim::*,
+	utils::*,
+	Despawn,
+};
+
+pub const SAVE_PATH: &str = "savegame.ron";
+// Bump whenever SaveFile changes shape, so that older saves are ignored instead of misread.
+const SAVE_VERSION: u32 = 5;
+
+// Read first, to check the version before trying to make sense of the rest of the file.
+#[derive(Deserialize)]
+struct SaveVersion {
+	version: u32,
+}
+
+// A whole run, written when the game is closed.
+#[derive(Serialize, Deserialize)]
+pub struct SaveFile {
+	version: u32,
+	seed: WorldSeed,
+	// WorldRng gets reseeded with this when saving, like the simulator's generator
+	world_rng_seed: u64,
+	floor: u32,
+	simulator: SimulatorState,
+	player: SavedPlayer,
+	mobs: Vec<SavedMob>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedPlayer {
+	position: Vec3,
+	health: i32,
+	gem_count: i32,
+	torches: u32,
+	torch_fuel: Vec<u32>,
+	level: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+enum SavedMobKind {
+	Enemy(EnemyKind),
+	Boss,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedMob {
+	kind: SavedMobKind,
+	position: Vec3,
+	health: i32,
+}
+
+impl SaveFile {
+	pub fn load(path: &str) -> Result<SaveFile, String> {
+		let text = fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+		let header: SaveVersion = ron::from_str(&text).map_err(|e| format!("{path}: {e}"))?;
+		if header.version != SAVE_VERSION {
+			return Err(format!(
+				"{path}: save version {} is not supported, expected {SAVE_VERSION}",
+				header.version
+			));
+		}
+		ron::from_str(&text).map_err(|e| format!("{path}: {e}"))
+	}
+
+	fn write(&self, path: &str) -> Result<(), String> {
+		let text = ron::to_string(self).map_err(|e| e.to_string())?;
+		fs::write(path, text).map_err(|e| format!("{path}: {e}"))
+	}
+}
+
+// A saved run the player can still pick back up, until they decline or start playing it.
+#[derive(Resource)]
+pub struct PendingSave(pub Option<SaveFile>);
+
+impl PendingSave {
+	pub fn from_disk(replay: &Replay) -> PendingSave {
+		// Recordings start from a fresh world, so saves are left alone while recording or replaying
+		if replay.is_on() || !Path::new(SAVE_PATH).exists() {
+			return PendingSave(None);
+		}
+		match SaveFile::load(SAVE_PATH) {
+			Ok(save) => PendingSave(Some(save)),
+			Err(e) => {
+				println!("Ignoring saved run: {e}");
+				PendingSave(None)
+			},
+		}
+	}
+}
+
+#[derive(Component)]
+pub struct ContinuePrompt;
+
+pub fn offer_continue(mut commands: Commands, asset_server: Res<AssetServer>, pending: Res<PendingSave>) {
+	if pending.0.is_none() {
+		return;
+	}
+	commands
+		.spawn(
+			TextBundle::from_section(
+				"C: continue the saved run    N: keep this new one",
+				TextStyle {
+					font: asset_server.load("fonts/DejaVuSans.ttf"),
+					font_size: 20.0,
+					color: Color::WHITE,
+				},
+			)
+			.with_style(Style {
+				position_type: PositionType::Absolute,
+				position: UiRect {
+					left: Val::Px(280.0),
+					top: Val::Px(120.0),
+					..default()
+				},
+				..default()
+			}),
+		)
+		.insert(ContinuePrompt);
+}
+
+// Swap the fresh run for the saved one when the player asks for it.
+pub fn continue_saved_run(
+	mut commands: Commands,
+	keyboard_input: Res<Input<KeyCode>>,
+	mut asset_server: Res<AssetServer>,
+	mut texture_atlases: ResMut<Assets<TextureAtlas>>,
+	mut pending: ResMut<PendingSave>,
+	mut simulator: ResMut<Simulator>,
+	mut seed: ResMut<WorldSeed>,
+	mut world_rng: ResMut<WorldRng>,
+	mut floor: ResMut<Floor>,
+	mut spawner: ResMut<EnemySpawner>,
+	mut players: Query<(&mut Transform, &mut Player)>,
+	prompts: Query<Entity, With<ContinuePrompt>>,
+	leftovers: Query<
+		Entity,
+		Or<(
+			With<DescentPortal>,
+			With<Mob>,
+			With<PlayerDanger>,
+			With<Projectile>,
+			With<Gem>,
+			With<Tile>,
+			With<BackTile>,
+			With<Structure>,
+		)>,
+	>,
+) {
+	let resume = keyboard_input.just_pressed(KeyCode::C);
+	if pending.0.is_none() || !(resume || keyboard_input.just_pressed(KeyCode::N)) {
+		return;
+	}
+	for prompt in prompts.iter() {
+		commands.entity(prompt).insert(Despawn);
+	}
+	let save = pending.0.take().unwrap();
+	if !resume {
+		return;
+	}
+
+	println!("Continuing saved run, world seed: {}", save.seed.0);
+	*seed = save.seed;
+	*world_rng = WorldRng(StdRng::seed_from_u64(save.world_rng_seed));
+	*floor = Floor(save.floor);
+	*spawner = EnemySpawner::for_floor(floor.0);
+	*simulator = Simulator::restore(save.simulator);
+
+	for entity in leftovers.iter() {
+		commands.entity(entity).insert(Despawn);
+	}
+	let (mut transform, mut player) = players.single_mut();
+	transform.translation = save.player.position;
+	player.health = save.player.health;
+	player.gem_count = save.player.gem_count;
+	player.torches = save.player.torches;
+	player.torch_fuel = save.player.torch_fuel;
+	player.level = save.player.level;
+	for mob in save.mobs {
+		let entity = match mob.kind {
+			SavedMobKind::Enemy(EnemyKind::Ranger) => {
+				spawn_ranger(&mut commands, &mut asset_server, &mut texture_atlases, mob.position)
+			},
+			SavedMobKind::Enemy(EnemyKind::Wraith) => spawn_wraith(&mut commands, &mut asset_server, mob.position),
+			SavedMobKind::Enemy(EnemyKind::Goo) => spawn_goo(&mut commands, &mut asset_server, mob.position),
+			SavedMobKind::Boss => spawn_boss(&mut commands, &asset_server, mob.position.truncate()),
+		};
+		commands.entity(entity).insert(Mob { health: mob.health });
+	}
+}
+
+// Write the run out when the window gets closed, so that it can be continued on the next launch.
+pub fn autosave_on_exit(
+	mut close_requests: EventReader<WindowCloseRequested>,
+	mut exits: EventReader<AppExit>,
+	mut saved: Local<bool>,
+	replay: Res<Replay>,
+	pending: Res<PendingSave>,
+	mut simulator: ResMut<Simulator>,
+	seed: Res<WorldSeed>,
+	mut world_rng: ResMut<WorldRng>,
+	floor: Res<Floor>,
+	players: Query<(&Transform, &Player)>,
+	mobs: Query<(
+		&Transform,
+		&Mob,
+		Option<&EnemyRanger>,
+		Option<&EnemyWraith>,
+		Option<&EnemyGoo>,
+		Option<&EnemyBoss>,
+	)>,
+) {
+	let quitting = close_requests.iter().count() > 0 || exits.iter().count() > 0;
+	if !quitting || *saved || replay.is_on() {
+		// A recorded or replayed run is not the player's to keep
+		return;
+	}
+	*saved = true;
+	if pending.0.is_some() {
+		// The player never gave up the saved run for this one
+		return;
+	}
+	let (transform, player) = players.single();
+	if player.health <= 0 {
+		// Nothing worth continuing, and the saved run was given up for this one
+		if let Err(e) = fs::remove_file(SAVE_PATH) {
+			if e.kind() != std::io::ErrorKind::NotFound {
+				println!("Could not delete the saved run: {e}");
+			}
+		}
+		return;
+	}
+
+	let world_rng_seed = world_rng.0.gen();
+	world_rng.0 = StdRng::seed_from_u64(world_rng_seed);
+	let mut saved_mobs = Vec::new();
+	for (transform, mob, ranger, wraith, goo, boss) in mobs.iter() {
+		let kind = match (ranger, wraith, goo, boss) {
+			(_, _, _, Some(_)) => SavedMobKind::Boss,
+			(Some(_), _, _, _) => SavedMobKind::Enemy(EnemyKind::Ranger),
+			(_, Some(_), _, _) => SavedMobKind::Enemy(EnemyKind::Wraith),
+			(_, _, Some(_), _) => SavedMobKind::Enemy(EnemyKind::Goo),
+			_ => continue,
+		};
+		if mob.health > 0 {
+			saved_mobs.push(SavedMob {
+				kind: kind,
+				position: transform.translation,
+				health: mob.health,
+			});
+		}
+	}
+	let save = SaveFile {
+		version: SAVE_VERSION,
+		seed: *seed,
+		world_rng_seed: world_rng_seed,
+		floor: floor.0,
+		simulator: simulator.snapshot(),
+		player: SavedPlayer {
+			position: transform.translation,
+			health: player.health,
+			gem_count: player.gem_count,
+			torches: player.torches,
+			torch_fuel: player.torch_fuel.clone(),
+			level: player.level,
+		},
+		mobs: saved_mobs,
+	};
+	match save.write(SAVE_PATH) {
+		Ok(()) => println!("Saved the run to {SAVE_PATH}"),
+		Err(e) => println!("Could not save the run: {e}"),
+	}
+}
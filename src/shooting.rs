@@ -1,10 +1,10 @@
 use std::f32::consts::TAU;
 
-use bevy::prelude::*;
+use bevy::{math::Vec3Swizzles, prelude::*};
 use bevy_rapier2d::prelude::*;
 use rand::Rng;
 
-use crate::{mob::*, player::*, Despawn};
+use crate::{mob::*, player::*, replay::Aim, utils::WorldRng, Despawn};
 
 #[derive(Component)]
 pub struct Projectile {
@@ -37,11 +37,26 @@ pub struct Mine {
 #[derive(Component, Deref, DerefMut)]
 pub struct ProjectileTimer(Timer);
 
+// Breaks the walls around where the projectile goes off, `radius` tiles out.
+#[derive(Component)]
+pub struct Blast {
+	pub radius: i32,
+}
+
+pub const MINE_BLAST_RADIUS: i32 = 2;
+
+// A projectile with a Blast went off, either on hitting something or at the end of its life.
+pub struct Detonation {
+	pub position: Vec2,
+	pub radius: i32,
+}
+
 pub fn player_shoot(
 	commands: Commands,
 	time: Res<Time>,
 	asset_server: Res<AssetServer>,
-	windows: Res<Windows>,
+	mut world_rng: ResMut<WorldRng>,
+	aim: Res<Aim>,
 	mouse_button_input: Res<Input<MouseButton>>,
 	mut player_query: Query<(
 		&mut FireboltCooldownTimer,
@@ -51,9 +66,7 @@ pub fn player_shoot(
 		&Velocity,
 		&Player,
 	)>,
-	camera_query: Query<(&Camera, &GlobalTransform)>,
 ) {
-	let (camera, camera_transform) = camera_query.single();
 	let (mut firebolt_timer, mut crystal_timer, mut mine_timer, player_transform, player_velocity, player) =
 		player_query.single_mut();
 
@@ -68,16 +81,15 @@ pub fn player_shoot(
 		return;
 	};
 
-	let window = windows.get_primary().unwrap();
-	if let Some(cursor_position) = window.cursor_position() {
+	if let Some(cursor_position) = aim.0 {
 		if mouse_button_input.pressed(MouseButton::Left) {
-			let cursor_position = get_cursor_world_pos(camera, camera_transform, window, cursor_position);
 			cast_spell(
 				commands,
 				player_transform,
 				player_velocity,
 				&player,
 				asset_server,
+				&mut world_rng.0,
 				cursor_position,
 			);
 			match player.select {
@@ -89,28 +101,13 @@ pub fn player_shoot(
 	}
 }
 
-fn get_cursor_world_pos(camera: &Camera, camera_transform: &GlobalTransform, window: &Window, cursor_position: Vec2) -> Vec2 {
-	let window_size = Vec2::new(window.width() as f32, window.height() as f32);
-
-	// convert screen position [0..resolution] to ndc [-1..1] (gpu coordinates)
-	let ndc = (cursor_position / window_size) * 2.0 - Vec2::ONE;
-
-	// matrix for undoing the projection and camera transform
-	let ndc_to_world = camera_transform.compute_matrix() * camera.projection_matrix().inverse();
-
-	// use it to convert ndc to world-space coordinates
-	let world_pos = ndc_to_world.project_point3(ndc.extend(-1.0));
-
-	// reduce it to a 2D value
-	return world_pos.truncate();
-}
-
-fn cast_spell(
+fn cast_spell<R: Rng>(
 	mut commands: Commands,
 	player_transform: &Transform,
 	player_velocity: &Velocity,
 	player: &Player,
 	asset_server: Res<AssetServer>,
+	rng: &mut R,
 	cursor_position: Vec2,
 ) {
 	let heading = (cursor_position - player_transform.translation.truncate()).normalize();
@@ -135,7 +132,7 @@ fn cast_spell(
 			));
 		},
 		PlayerWeaponSelect::Crystals => {
-			let a = rand::thread_rng().gen_range(-TAU / 12.0..TAU / 12.0);
+			let a = rng.gen_range(-TAU / 12.0..TAU / 12.0);
 			commands.spawn((
 				SpriteBundle {
 					texture: asset_server.load("crystal.png"),
@@ -158,7 +155,7 @@ fn cast_spell(
 			));
 		},
 		PlayerWeaponSelect::Mine => {
-			let a = rand::thread_rng().gen_range(-TAU / 12.0..TAU / 12.0);
+			let a = rng.gen_range(-TAU / 12.0..TAU / 12.0);
 			commands.spawn((
 				SpriteBundle {
 					texture: asset_server.load("fire_bolt.png"),
@@ -178,6 +175,9 @@ fn cast_spell(
 					basevel: player_velocity.linvel,
 					heading: heading.rotate(Vec2::from_angle(a)),
 				},
+				Blast {
+					radius: MINE_BLAST_RADIUS,
+				},
 			));
 		},
 		PlayerWeaponSelect::Mine => {},
@@ -208,11 +208,22 @@ pub fn update_mines_velocity(time: Res<Time>, mut mines: Query<(&mut ProjectileT
 	}
 }
 
-pub fn despawn_old_projectiles(mut commands: Commands, time: Res<Time>, mut query: Query<(Entity, &mut ProjectileTimer)>) {
-	for (entity, mut timer) in query.iter_mut() {
+pub fn despawn_old_projectiles(
+	mut commands: Commands,
+	time: Res<Time>,
+	mut detonations: EventWriter<Detonation>,
+	mut query: Query<(Entity, &Transform, &mut ProjectileTimer, Option<&Blast>)>,
+) {
+	for (entity, transform, mut timer, blast) in query.iter_mut() {
 		timer.tick(time.delta());
 		if timer.finished() {
 			commands.entity(entity).insert(Despawn);
+			if let Some(blast) = blast {
+				detonations.send(Detonation {
+					position: transform.translation.xy(),
+					radius: blast.radius,
+				});
+			}
 		}
 	}
 }
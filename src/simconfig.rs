@@ -0,0 +1,370 @@
+use std::{f32::consts::PI, fmt, fs, path::Path, str::FromStr};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	biomes::BiomeConfig,
+	carule::CaRuleConfig,
+	structures::Prefab,
+	tilemap::{atlas_range, TileSet, CAVE_ATLAS_LEN},
+};
+
+pub const SIMULATOR_CONFIG_PATH: &str = "assets/simulator.ron";
+
+// The boss altar sits this many tiles inside the outer radius of the maze.
+pub const BOSS_ROOM_INSET: u32 = 6;
+
+// Fraction of the plane covered by discs placed by random sequential adsorption once no more fit.
+// Rejection sampling in poisson_disk_sample cannot be expected to do better than this.
+const RSA_JAMMING_DENSITY: f32 = 0.547;
+
+// Every floor below the first forgets this much faster, and weighs fresh cells this much more towards walls.
+const FLOOR_HALF_LIFE_FACTOR: f32 = 0.75;
+const FLOOR_WALL_WEIGHT_STEP: u32 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UpdateMode {
+	// Cells are overwritten during the scan, so later cells see the new state of earlier ones.
+	// This makes walls grow towards the end of the scan.
+	InPlace,
+	// Every cell reads the previous generation of its neighbours.
+	Synchronous,
+}
+
+impl FromStr for UpdateMode {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"in-place" => Ok(UpdateMode::InPlace),
+			"synchronous" => Ok(UpdateMode::Synchronous),
+			_ => Err(format!("unknown update mode '{s}', expected 'in-place' or 'synchronous'")),
+		}
+	}
+}
+
+// Which cells the player remembers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MemoryModel {
+	// Everything within the outer reality radius, even behind walls.
+	Proximity,
+	// Only what the player can see within the outer reality radius, so rooms behind a wall get forgotten.
+	LineOfSight,
+}
+
+impl FromStr for MemoryModel {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"proximity" => Ok(MemoryModel::Proximity),
+			"line-of-sight" => Ok(MemoryModel::LineOfSight),
+			_ => Err(format!("unknown memory model '{s}', expected 'proximity' or 'line-of-sight'")),
+		}
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Resource, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SimulatorConfig {
+	// Side length of the square map, in tiles.
+	pub width: u32,
+	// Stream chunks in around the player instead of generating a width x width map up front.
+	// There is no solid rock beyond the outer radius then, which only places the boss room.
+	pub infinite: bool,
+	// How cells near the player evolve.
+	pub ca_rule: CaRuleConfig,
+	// Cells closer than this to the world center are always open.
+	pub inner_radius: u32,
+	// Cells further than this from the world center are always walls.
+	pub outer_radius: u32,
+	// Relative odds of a fresh cell being open or a wall.
+	pub floor_weight: u32,
+	pub wall_weight: u32,
+	// Relative odds of the other kinds of fresh cells, next to floor_weight for open ones and wall_weight for solid ones.
+	pub water_weight: u32,
+	pub chasm_weight: u32,
+	pub rubble_weight: u32,
+	pub crystal_vein_weight: u32,
+	// Campfires keep cells this close remembered. The radius shrinks as they burn down.
+	pub campfire_radius: u32,
+	// Number of steps a campfire burns for before it goes out, when lit or refuelled.
+	pub campfire_fuel: u32,
+	// The cellular automaton only runs between these distances from the player,
+	// and cells further than the outer one can be forgotten.
+	pub reality_inner_radius: u32,
+	pub reality_outer_radius: u32,
+	// Number of steps after which the memory of a cell outside of the reality bubble has halved.
+	pub half_life: u32,
+	// Cells are forgotten and generated afresh once their memory falls below this.
+	pub forget_below: f32,
+	pub memory_model: MemoryModel,
+	pub n_structures: u32,
+	pub structure_dist: u32,
+	// Space kept open around structures that are not stamped with a prefab.
+	pub structure_radius: u32,
+	// Rooms for the structure sites. Without any, the sites get a disc of structure_radius cleared around them.
+	pub prefabs: Vec<Prefab>,
+	pub boss_room_radius: u32,
+	// Carve tunnels so that the player can always reach the boss room and the nearest campfire.
+	pub ensure_connectivity: bool,
+	pub update_mode: UpdateMode,
+	// Average distance between the centers of biome regions.
+	pub biome_size: u32,
+	// Biomes besides the base cave described by the fields above.
+	pub biomes: Vec<BiomeConfig>,
+}
+
+impl Default for SimulatorConfig {
+	fn default() -> Self {
+		Self {
+			width: 200,
+			infinite: false,
+			ca_rule: CaRuleConfig::default(),
+			inner_radius: 10,
+			outer_radius: 94,
+			floor_weight: 10,
+			wall_weight: 13,
+			water_weight: 0,
+			chasm_weight: 0,
+			rubble_weight: 0,
+			crystal_vein_weight: 0,
+			campfire_radius: 15,
+			campfire_fuel: 600,
+			reality_inner_radius: 20,
+			reality_outer_radius: 30,
+			half_life: 10,
+			forget_below: 0.25,
+			memory_model: MemoryModel::Proximity,
+			n_structures: 10,
+			structure_dist: 20,
+			structure_radius: 5,
+			prefabs: Vec::new(),
+			boss_room_radius: 20,
+			ensure_connectivity: true,
+			update_mode: UpdateMode::Synchronous,
+			biome_size: 48,
+			biomes: Vec::new(),
+		}
+	}
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+	Io(std::io::Error),
+	Parse(ron::error::SpannedError),
+	Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ConfigError::Io(e) => write!(f, "could not read simulator config: {e}"),
+			ConfigError::Parse(e) => write!(f, "could not parse simulator config: {e}"),
+			ConfigError::Invalid(e) => write!(f, "invalid simulator config: {e}"),
+		}
+	}
+}
+
+impl std::error::Error for ConfigError {}
+
+impl SimulatorConfig {
+	pub fn load(path: impl AsRef<Path>) -> Result<SimulatorConfig, ConfigError> {
+		let text = fs::read_to_string(path).map_err(ConfigError::Io)?;
+		let config: SimulatorConfig = ron::from_str(&text).map_err(ConfigError::Parse)?;
+		config.validate()?;
+		Ok(config)
+	}
+
+	pub fn validate(&self) -> Result<(), ConfigError> {
+		let invalid = |msg: String| Err(ConfigError::Invalid(msg));
+		if let Err(e) = self.ca_rule.build() {
+			return invalid(format!("cellular automaton rule: {e}"));
+		}
+		if self.inner_radius >= self.outer_radius {
+			return invalid(format!(
+				"inner radius ({}) must be smaller than outer radius ({})",
+				self.inner_radius, self.outer_radius
+			));
+		}
+		if self.outer_radius < BOSS_ROOM_INSET || (!self.infinite && self.outer_radius >= self.width / 2) {
+			return invalid(format!(
+				"outer radius ({}) must be between {} and half the width ({})",
+				self.outer_radius,
+				BOSS_ROOM_INSET,
+				self.width / 2
+			));
+		}
+		if self.reality_inner_radius >= self.reality_outer_radius {
+			return invalid(format!(
+				"reality bubble inner radius ({}) must be smaller than its outer radius ({})",
+				self.reality_inner_radius, self.reality_outer_radius
+			));
+		}
+		let base = &self.biome_configs()[0];
+		if base.open_weight() + base.solid_weight() == 0 {
+			return invalid("tile weights cannot all be zero".into());
+		}
+		if self.campfire_fuel == 0 {
+			return invalid("campfire fuel must be positive".into());
+		}
+		if self.half_life == 0 {
+			return invalid("half life must be positive".into());
+		}
+		if !(self.forget_below > 0. && self.forget_below < 1.) {
+			return invalid(format!("forget_below ({}) must be between 0 and 1", self.forget_below));
+		}
+		if self.biome_size == 0 {
+			return invalid("biome size must be positive".into());
+		}
+		if self.biomes.len() >= u8::MAX as usize {
+			return invalid(format!("at most {} extra biomes are supported", u8::MAX - 1));
+		}
+		for biome in self.biomes.iter() {
+			if let Err(e) = biome.ca_rule.build() {
+				return invalid(format!("biome {}: cellular automaton rule: {e}", biome.name));
+			}
+			if biome.open_weight() + biome.solid_weight() == 0 {
+				return invalid(format!("biome {}: tile weights cannot all be zero", biome.name));
+			}
+			let offsets = [
+				("wall_atlas_offset", TileSet::Wall, biome.wall_atlas_offset),
+				("floor_atlas_offset", TileSet::Floor, biome.floor_atlas_offset),
+			];
+			for (name, tile_set, offset) in offsets {
+				let (min, max) = atlas_range(tile_set);
+				if min as i32 + offset < 0 || max as i32 + offset >= CAVE_ATLAS_LEN as i32 {
+					return invalid(format!(
+						"biome {}: {name} ({offset}) moves tiles {min}-{max} out of the {CAVE_ATLAS_LEN} tile cave atlas",
+						biome.name
+					));
+				}
+			}
+		}
+		// Structures are sampled from the annulus between the reality bubble around the spawn and the outer radius.
+		// Each one claims a disc of radius structure_dist / 2 that cannot overlap with the others.
+		let claim = self.structure_dist as f32 / 2.;
+		let outer = self.outer_radius as f32 + claim;
+		let inner = (self.reality_outer_radius as f32 - claim).max(0.);
+		let available = RSA_JAMMING_DENSITY * PI * (outer * outer - inner * inner);
+		let needed = self.n_structures as f32 * PI * claim * claim;
+		if self.n_structures > 0 && (self.reality_outer_radius >= self.outer_radius || needed > available) {
+			return invalid(format!(
+				"cannot place {} structures at least {} tiles apart between radii {} and {}",
+				self.n_structures, self.structure_dist, self.reality_outer_radius, self.outer_radius
+			));
+		}
+		// Rooms on sites structure_dist apart cannot overlap as long as they fit in the disc each site claims
+		for prefab in self.prefabs.iter() {
+			let cells = match prefab.cells() {
+				Ok(cells) => cells,
+				Err(e) => return invalid(e),
+			};
+			if cells.iter().any(|(offset, _)| offset.as_vec2().length() > claim) {
+				return invalid(format!(
+					"prefab {} does not fit within half of structure_dist ({claim}) of its middle",
+					prefab.name
+				));
+			}
+		}
+		if !self.prefabs.is_empty() && self.prefabs.iter().all(|prefab| prefab.weight == 0) {
+			return invalid("prefab weights cannot all be zero".into());
+		}
+		Ok(())
+	}
+
+	// Harder version of this config for the given floor, counting from 1.
+	pub fn for_floor(&self, floor: u32) -> SimulatorConfig {
+		let depth = floor.saturating_sub(1);
+		let half_life = self.half_life as f32 * FLOOR_HALF_LIFE_FACTOR.powi(depth as i32);
+		let denser = |biome: &BiomeConfig| BiomeConfig {
+			wall_weight: biome.wall_weight + depth * FLOOR_WALL_WEIGHT_STEP,
+			..biome.clone()
+		};
+		SimulatorConfig {
+			half_life: (half_life.round() as u32).max(1),
+			wall_weight: self.wall_weight + depth * FLOOR_WALL_WEIGHT_STEP,
+			biomes: self.biomes.iter().map(denser).collect(),
+			..self.clone()
+		}
+	}
+
+	// All biomes, starting with the base cave.
+	pub fn biome_configs(&self) -> Vec<BiomeConfig> {
+		let base = BiomeConfig {
+			ca_rule: self.ca_rule.clone(),
+			floor_weight: self.floor_weight,
+			wall_weight: self.wall_weight,
+			water_weight: self.water_weight,
+			chasm_weight: self.chasm_weight,
+			rubble_weight: self.rubble_weight,
+			crystal_vein_weight: self.crystal_vein_weight,
+			..default()
+		};
+		std::iter::once(base).chain(self.biomes.iter().cloned()).collect()
+	}
+
+	pub fn world_center(&self) -> IVec2 {
+		if self.infinite {
+			return IVec2::ZERO;
+		}
+		return IVec2::splat((self.width / 2) as i32);
+	}
+
+	// Factor the memory of a cell outside of the reality bubble is multiplied by in a single step.
+	pub fn memory_decay(&self) -> f32 {
+		0.5_f32.powf(1.0 / self.half_life as f32)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// Why validation turns a config down.
+	fn rejection(config: SimulatorConfig) -> String {
+		match config.validate() {
+			Err(ConfigError::Invalid(msg)) => msg,
+			other => panic!("expected the config to be rejected, got {other:?}"),
+		}
+	}
+
+	#[test]
+	fn shipped_config_loads() {
+		SimulatorConfig::load(SIMULATOR_CONFIG_PATH).unwrap();
+	}
+
+	#[test]
+	fn inner_radius_must_be_inside_outer_radius() {
+		let config = SimulatorConfig {
+			inner_radius: 94,
+			outer_radius: 94,
+			..default()
+		};
+		assert!(rejection(config).contains("inner radius (94) must be smaller than outer radius (94)"));
+	}
+
+	#[test]
+	fn too_many_structures_are_rejected() {
+		let config = SimulatorConfig {
+			n_structures: 1000,
+			..default()
+		};
+		assert!(rejection(config).contains("cannot place 1000 structures"));
+	}
+
+	#[test]
+	fn prefabs_must_fit_their_site() {
+		// structure_dist 20 leaves a radius of 10 around the middle, which a 23 wide room overshoots
+		let config = SimulatorConfig {
+			prefabs: vec![Prefab {
+				name: "hall".into(),
+				weight: 1,
+				rows: vec![".".repeat(23)],
+			}],
+			..default()
+		};
+		assert!(rejection(config).contains("prefab hall does not fit"));
+	}
+}
@@ -1,9 +1,10 @@
 use bevy::prelude::*;
-use rand::*;
+use rand::{distributions::WeightedIndex, prelude::*};
+use serde::{Deserialize, Serialize};
 
-use crate::tilesim::Simulator;
+use crate::tilesim::TileKind;
 
-#[derive(Clone, Debug, FromReflect, Reflect)]
+#[derive(Clone, Debug, FromReflect, Reflect, Serialize, Deserialize)]
 pub enum StructureType {
 	Unspawned,
 	SpawnTutorial,
@@ -33,14 +34,14 @@ pub fn get_structure_texture(structure_type: &StructureType, asset_server: &Asse
 	}
 }
 
-pub fn decide_structure_type(boss_room_loc: UVec2, loc: UVec2) -> StructureType {
+pub fn decide_structure_type<R: Rng>(rng: &mut R, boss_room_loc: IVec2, loc: IVec2) -> StructureType {
 	if loc == boss_room_loc {
 		return StructureType::BossAltar;
 	}
-	if (thread_rng().gen::<f32>()) < 0.3 {
+	if (rng.gen::<f32>()) < 0.3 {
 		return StructureType::Altar;
 	}
-	match thread_rng().gen_range(0..=5) {
+	match rng.gen_range(0..=5) {
 		0 => StructureType::Remember,
 		1 => StructureType::BewareSpider,
 		2 => StructureType::FearTheSpider,
@@ -51,4 +52,179 @@ pub fn decide_structure_type(boss_room_loc: UVec2, loc: UVec2) -> StructureType
 	}
 }
 
-pub fn generate_custom_structures(simulator: &mut Simulator) {}
+// Spots in a prefab room that the game fills in once the player gets close.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PrefabSlot {
+	// A lit campfire
+	Campfire,
+	// A pile of gems
+	Loot,
+	// An enemy from the biome the room is in
+	EnemySpawn,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PrefabCell {
+	Wall,
+	Floor,
+	// Rock standing inside the room, as opposed to its outline
+	Pillar,
+	Slot(PrefabSlot),
+}
+
+impl PrefabCell {
+	// None for tiles left to the cave.
+	fn from_char(c: char) -> Result<Option<PrefabCell>, String> {
+		match c {
+			' ' => Ok(None),
+			'#' => Ok(Some(PrefabCell::Wall)),
+			'.' => Ok(Some(PrefabCell::Floor)),
+			'O' => Ok(Some(PrefabCell::Pillar)),
+			'*' => Ok(Some(PrefabCell::Slot(PrefabSlot::Campfire))),
+			'$' => Ok(Some(PrefabCell::Slot(PrefabSlot::Loot))),
+			'!' => Ok(Some(PrefabCell::Slot(PrefabSlot::EnemySpawn))),
+			_ => Err(format!("unknown tile '{c}', expected one of \"#.O*$!\" or a space")),
+		}
+	}
+
+	pub fn kind(self) -> TileKind {
+		match self {
+			PrefabCell::Wall | PrefabCell::Pillar => TileKind::Wall,
+			PrefabCell::Floor | PrefabCell::Slot(_) => TileKind::Floor,
+		}
+	}
+}
+
+// A hand-drawn room that structure sites get stamped with. Every row is a line of the room from the top down,
+// with one character per tile:
+// # wall, . floor, O pillar, * campfire slot, $ loot slot, ! enemy spawn point, and a space for tiles left to the cave.
+// The middle of the template lands on the site, where the structure stands, so it has to be floor.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Prefab {
+	pub name: String,
+	// Relative odds of a site getting this room
+	pub weight: u32,
+	pub rows: Vec<String>,
+}
+
+impl Prefab {
+	// The tiles the room sets, relative to its middle.
+	pub fn cells(&self) -> Result<Vec<(IVec2, PrefabCell)>, String> {
+		let height = self.rows.len() as i32;
+		let width = self.rows.iter().map(|row| row.chars().count()).max().unwrap_or(0) as i32;
+		if width == 0 {
+			return Err(format!("prefab {} is empty", self.name));
+		}
+		let mut cells = Vec::new();
+		for (y, row) in self.rows.iter().enumerate() {
+			for (x, c) in row.chars().enumerate() {
+				let cell = PrefabCell::from_char(c).map_err(|e| format!("prefab {}: {e}", self.name))?;
+				if let Some(cell) = cell {
+					// Rows go from the top of the room to the bottom, like on screen
+					cells.push((IVec2::new(x as i32 - width / 2, height / 2 - y as i32), cell));
+				}
+			}
+		}
+		if !cells.contains(&(IVec2::ZERO, PrefabCell::Floor)) {
+			return Err(format!("the middle of prefab {} must be floor", self.name));
+		}
+		return Ok(cells);
+	}
+}
+
+// Index of a prefab picked by weight, or None if there are none to pick from.
+pub fn choose_prefab<R: Rng>(rng: &mut R, prefabs: &[Prefab]) -> Option<usize> {
+	let weights = WeightedIndex::new(prefabs.iter().map(|prefab| prefab.weight)).ok()?;
+	return Some(weights.sample(rng));
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn prefab(rows: &[&str]) -> Prefab {
+		Prefab {
+			name: "test".into(),
+			weight: 1,
+			rows: rows.iter().map(|row| row.to_string()).collect(),
+		}
+	}
+
+	fn cell(x: i32, y: i32, cell: PrefabCell) -> (IVec2, PrefabCell) {
+		(IVec2::new(x, y), cell)
+	}
+
+	#[test]
+	fn odd_size_is_centred() {
+		let cells = prefab(&["#.#", "...", "#*#"]).cells().unwrap();
+		assert_eq!(
+			cells,
+			vec![
+				cell(-1, 1, PrefabCell::Wall),
+				cell(0, 1, PrefabCell::Floor),
+				cell(1, 1, PrefabCell::Wall),
+				cell(-1, 0, PrefabCell::Floor),
+				cell(0, 0, PrefabCell::Floor),
+				cell(1, 0, PrefabCell::Floor),
+				cell(-1, -1, PrefabCell::Wall),
+				cell(0, -1, PrefabCell::Slot(PrefabSlot::Campfire)),
+				cell(1, -1, PrefabCell::Wall),
+			]
+		);
+	}
+
+	#[test]
+	fn even_size_middle_is_right_of_and_below_centre() {
+		let cells = prefab(&["#O.#", "$..!"]).cells().unwrap();
+		assert_eq!(
+			cells,
+			vec![
+				cell(-2, 1, PrefabCell::Wall),
+				cell(-1, 1, PrefabCell::Pillar),
+				cell(0, 1, PrefabCell::Floor),
+				cell(1, 1, PrefabCell::Wall),
+				cell(-2, 0, PrefabCell::Slot(PrefabSlot::Loot)),
+				cell(-1, 0, PrefabCell::Floor),
+				cell(0, 0, PrefabCell::Floor),
+				cell(1, 0, PrefabCell::Slot(PrefabSlot::EnemySpawn)),
+			]
+		);
+	}
+
+	#[test]
+	fn ragged_rows_leave_the_rest_to_the_cave() {
+		// The widest row sets the width, and shorter rows start at the left edge
+		let cells = prefab(&["  #", "..", "O"]).cells().unwrap();
+		assert_eq!(
+			cells,
+			vec![
+				cell(1, 1, PrefabCell::Wall),
+				cell(-1, 0, PrefabCell::Floor),
+				cell(0, 0, PrefabCell::Floor),
+				cell(-1, -1, PrefabCell::Pillar),
+			]
+		);
+	}
+
+	#[test]
+	fn unknown_characters_are_rejected() {
+		let err = prefab(&["...", ".x.", "..."]).cells().unwrap_err();
+		assert!(err.contains("unknown tile 'x'"), "{err}");
+		assert!(prefab(&["...", "...", "..\t"]).cells().is_err());
+	}
+
+	#[test]
+	fn middle_must_be_floor() {
+		assert!(prefab(&["...", ".#.", "..."]).cells().is_err());
+		assert!(prefab(&["...", ".O.", "..."]).cells().is_err());
+		assert!(prefab(&["...", ".*.", "..."]).cells().is_err());
+		// Nothing at all in the middle
+		assert!(prefab(&["...", ". .", "..."]).cells().is_err());
+		// Flat and narrow rooms still have a middle, empty ones do not
+		assert!(prefab(&["..."]).cells().is_ok());
+		assert!(prefab(&[".."]).cells().is_ok());
+		assert!(prefab(&["."]).cells().is_ok());
+		assert!(prefab(&[]).cells().is_err());
+		assert!(prefab(&[""]).cells().is_err());
+	}
+}
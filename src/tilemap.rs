@@ -1,30 +1,34 @@
 use bevy::prelude::*;
 
-use crate::tilesim::Simulator;
+use crate::tilesim::{Simulator, TileKind};
 
-pub fn tile_position_rand(tile_position: UVec2) -> usize {
+pub fn tile_position_rand(tile_position: IVec2) -> usize {
 	((31 * tile_position.x + 37 * tile_position.y + 1337) ^ (tile_position.x * 7 + tile_position.y * 11)) as usize
 }
 
-#[rustfmt::skip]
-pub fn tile_atlas_index(simulator: &Simulator, tile_position: UVec2) -> usize {
-	let f = |dx: i32, dy: i32| -> bool {
-		let xx = ((tile_position.x as i32) + dx) as usize;
-		let yy = ((tile_position.y as i32) + dy) as usize;
-		simulator
-			.grid
-			.is_wall
-			.get(xx)
-			.map_or(false, |row| *row.get(yy).unwrap_or(&false))
-	};
-	let v = tile_position_rand(tile_position);
+// Which of a biome's atlas offsets applies to a tile.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TileSet {
+	Wall,
+	Floor,
+}
 
-	const X: i32 = 1; // wall
-	const T: i32 = 0; // any
-	const O: i32 = -1; // open
+// The cave atlas is a grid of 51 x 48 tiles.
+pub const CAVE_ATLAS_LEN: usize = 51 * 48;
 
+// Every pattern picks its variation from v modulo a divisor of this.
+const VARIATIONS: usize = 180;
+
+const X: i32 = 1; // wall, or the same kind for water, chasm and rubble
+const T: i32 = 0; // any
+const O: i32 = -1; // open, or another kind
+
+// Neighbourhoods a tile can be in, with the cave atlas index of the tile drawn there before the
+// biome's offset is added, and which offset that is. The first pattern that matches wins.
+#[rustfmt::skip]
+fn patterns(v: usize) -> [([[i32; 3]; 4], usize, TileSet); 11] {
 	// pattern center at (1, 1)
-	let patterns = [
+	return [
 		(
 			[ // up
 				[T, O, T],
@@ -32,7 +36,8 @@ pub fn tile_atlas_index(simulator: &Simulator, tile_position: UVec2) -> usize {
 				[T, T, T],
 				[T, T, T]
 			],
-			613 + v%4
+			613 + v%4,
+			TileSet::Wall
 		),
 		(
 			[ // up left
@@ -41,7 +46,8 @@ pub fn tile_atlas_index(simulator: &Simulator, tile_position: UVec2) -> usize {
 				[T, X, T],
 				[T, T, T]
 			],
-			2
+			2,
+			TileSet::Wall
 		),
 		(
 			[ // up right
@@ -50,7 +56,8 @@ pub fn tile_atlas_index(simulator: &Simulator, tile_position: UVec2) -> usize {
 				[T, X, T],
 				[T, T, T]
 			],
-			7
+			7,
+			TileSet::Wall
 		),
 		(
 			[ // right
@@ -59,7 +66,8 @@ pub fn tile_atlas_index(simulator: &Simulator, tile_position: UVec2) -> usize {
 				[T, X, T],
 				[T, T, T]
 			],
-			58 + 51 * (v%6)
+			58 + 51 * (v%6),
+			TileSet::Wall
 		),
 		(
 			[ // left
@@ -68,7 +76,8 @@ pub fn tile_atlas_index(simulator: &Simulator, tile_position: UVec2) -> usize {
 				[T, X, T],
 				[T, T, T]
 			],
-			53 + 51 * (v%6)
+			53 + 51 * (v%6),
+			TileSet::Wall
 		),
 		(
 			[ // down low
@@ -77,7 +86,8 @@ pub fn tile_atlas_index(simulator: &Simulator, tile_position: UVec2) -> usize {
 				[T, T, T],
 				[T, T, T]
 			],
-			562 + v%4
+			562 + v%4,
+			TileSet::Wall
 		),
 		(
 			[ // down mid
@@ -86,7 +96,8 @@ pub fn tile_atlas_index(simulator: &Simulator, tile_position: UVec2) -> usize {
 				[T, O, T],
 				[T, T, T]
 			],
-			511 + v%4
+			511 + v%4,
+			TileSet::Wall
 		),
 		(
 			[ // down high
@@ -95,7 +106,8 @@ pub fn tile_atlas_index(simulator: &Simulator, tile_position: UVec2) -> usize {
 				[T, X, T],
 				[T, O, T]
 			],
-			460 + v%4
+			460 + v%4,
+			TileSet::Wall
 		),
 		(
 			[ // mid
@@ -104,7 +116,8 @@ pub fn tile_atlas_index(simulator: &Simulator, tile_position: UVec2) -> usize {
 				[X, X, X],
 				[T, T, T]
 			],
-			54 + v%4 + 51*((v/4)%5)
+			54 + v%4 + 51*((v/4)%5),
+			TileSet::Wall
 		),
 		(
 			[ // generic open
@@ -113,7 +126,8 @@ pub fn tile_atlas_index(simulator: &Simulator, tile_position: UVec2) -> usize {
 				[T, T, T],
 				[T, T, T]
 			],
-			1775 + v%3 + 51*((v/3)%3)
+			floor_target(v),
+			TileSet::Floor
 		),
 		(
 			[ // generic wall
@@ -122,21 +136,220 @@ pub fn tile_atlas_index(simulator: &Simulator, tile_position: UVec2) -> usize {
 				[T, T, T],
 				[T, T, T]
 			],
-			208
+			208,
+			TileSet::Wall
+		)
+	];
+}
+
+// The edges of a pool, with its rocky shore along the top. Water looks the same in every biome.
+#[rustfmt::skip]
+fn water_patterns(v: usize) -> [([[i32; 3]; 4], usize); 9] {
+	return [
+		(
+			[ // up left
+				[T, O, T],
+				[O, X, T],
+				[T, T, T],
+				[T, T, T]
+			],
+			2187
+		),
+		(
+			[ // up right
+				[T, O, T],
+				[T, X, O],
+				[T, T, T],
+				[T, T, T]
+			],
+			2189
+		),
+		(
+			[ // up
+				[T, O, T],
+				[T, X, T],
+				[T, T, T],
+				[T, T, T]
+			],
+			2188
+		),
+		(
+			[ // down left
+				[T, T, T],
+				[O, X, T],
+				[T, O, T],
+				[T, T, T]
+			],
+			2340
+		),
+		(
+			[ // down right
+				[T, T, T],
+				[T, X, O],
+				[T, O, T],
+				[T, T, T]
+			],
+			2342
+		),
+		(
+			[ // down
+				[T, T, T],
+				[T, X, T],
+				[T, O, T],
+				[T, T, T]
+			],
+			2341
+		),
+		(
+			[ // left
+				[T, T, T],
+				[O, X, T],
+				[T, T, T],
+				[T, T, T]
+			],
+			2238 + 51 * (v%2)
+		),
+		(
+			[ // right
+				[T, T, T],
+				[T, X, O],
+				[T, T, T],
+				[T, T, T]
+			],
+			2240 + 51 * (v%2)
+		),
+		(
+			[ // mid
+				[T, T, T],
+				[T, X, T],
+				[T, T, T],
+				[T, T, T]
+			],
+			2239 + 51 * (v%2)
+		)
+	];
+}
+
+// A chasm shows its far wall along the top and is black everywhere else.
+#[rustfmt::skip]
+fn chasm_patterns(v: usize) -> [([[i32; 3]; 4], usize); 2] {
+	return [
+		(
+			[ // up
+				[T, O, T],
+				[T, X, T],
+				[T, T, T],
+				[T, T, T]
+			],
+			97 + v%4
+		),
+		(
+			[ // mid
+				[T, T, T],
+				[T, X, T],
+				[T, T, T],
+				[T, T, T]
+			],
+			149 + v%3 + 51 * ((v/3)%2)
+		)
+	];
+}
+
+// The atlas has no edges for rubble, so it thins out towards the edge of a heap instead.
+#[rustfmt::skip]
+fn rubble_patterns(v: usize) -> [([[i32; 3]; 4], usize); 2] {
+	return [
+		(
+			[ // mid
+				[T, X, T],
+				[X, X, X],
+				[T, X, T],
+				[T, T, T]
+			],
+			2142 + v%4 + 51 * ((v/4)%2)
+		),
+		(
+			[ // edge
+				[T, T, T],
+				[T, X, T],
+				[T, T, T],
+				[T, T, T]
+			],
+			2244 + v%4
 		)
 	];
+}
 
-	for (pattern, target) in patterns {
-		let mut ok = true;
-		for (dy, row) in (-1..=2).zip(pattern) {
-			for (dx, a) in (-1..=1).zip(row) {
-				if !((f(dx, -dy) && a >= 0) || (!f(dx, -dy) && a <= 0)) {
-					ok = false;
-					break;
-				}
+// Whether the neighbourhood of a tile fits a pattern, given which of its neighbours count as X.
+fn matches(pattern: [[i32; 3]; 4], f: &impl Fn(i32, i32) -> bool) -> bool {
+	for (dy, row) in (-1..=2).zip(pattern) {
+		for (dx, a) in (-1..=1).zip(row) {
+			if !((f(dx, -dy) && a >= 0) || (!f(dx, -dy) && a <= 0)) {
+				return false;
 			}
 		}
-		if ok { return target; }
+	}
+	return true;
+}
+
+// Cave atlas index drawn for the first pattern that matches.
+fn first_match<const N: usize>(patterns: [([[i32; 3]; 4], usize); N], f: &impl Fn(i32, i32) -> bool) -> usize {
+	let (_, target) = patterns.into_iter().find(|(pattern, _)| matches(*pattern, f)).unwrap();
+	return target;
+}
+
+// Lowest and highest cave atlas index, before the offset, of the tiles drawn from a tile set.
+pub fn atlas_range(tile_set: TileSet) -> (usize, usize) {
+	let targets = (0..VARIATIONS)
+		.flat_map(patterns)
+		.filter(|(_, _, set)| *set == tile_set)
+		.map(|(_, target, _)| target);
+	let (min, max) = targets.fold((usize::MAX, 0), |(min, max), target| (min.min(target), max.max(target)));
+	return (min, max);
+}
+
+// Plain floor, which is also drawn beneath every tile.
+fn floor_target(v: usize) -> usize {
+	1775 + v % 3 + 51 * ((v / 3) % 3)
+}
+
+fn with_offset(simulator: &Simulator, tile_position: IVec2, target: usize, tile_set: TileSet) -> usize {
+	let biome = simulator.biome(tile_position);
+	let offset = match tile_set {
+		TileSet::Wall => biome.wall_atlas_offset,
+		TileSet::Floor => biome.floor_atlas_offset,
+	};
+	return (target as i32 + offset) as usize;
+}
+
+pub fn floor_atlas_index(simulator: &Simulator, tile_position: IVec2) -> usize {
+	let v = tile_position_rand(tile_position);
+	return with_offset(simulator, tile_position, floor_target(v), TileSet::Floor);
+}
+
+#[rustfmt::skip]
+pub fn tile_atlas_index(simulator: &Simulator, tile_position: IVec2) -> usize {
+	let f = |dx: i32, dy: i32| -> bool {
+		simulator.grid.wall(tile_position + IVec2::new(dx, dy)).unwrap_or(false)
+	};
+	let v = tile_position_rand(tile_position);
+
+	// Open tiles other than floor look the same in every biome and join up with their own kind.
+	// Crystal veins are walls tinted in update_tiles.
+	let kind = simulator.grid.tile_kind(tile_position);
+	let same_kind = |dx: i32, dy: i32| -> bool {
+		simulator.grid.tile_kind(tile_position + IVec2::new(dx, dy)) == kind
+	};
+	match kind {
+		TileKind::Water => return first_match(water_patterns(v), &same_kind),
+		TileKind::Chasm => return first_match(chasm_patterns(v), &same_kind),
+		TileKind::Rubble => return first_match(rubble_patterns(v), &same_kind),
+		_ => {},
+	}
+
+	// The biome picks which tile set walls and floors come from
+	for (pattern, target, tile_set) in patterns(v) {
+		if matches(pattern, &f) { return with_offset(simulator, tile_position, target, tile_set); }
 	};
 	panic!();
 }
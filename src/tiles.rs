@@ -1,18 +1,32 @@
-use std::cmp::min;
+use bevy::{math::Vec3Swizzles, prelude::*, render::texture::ImageSampler, utils::HashSet};
+use bevy_rapier2d::prelude::*;
+use image::{DynamicImage, ImageBuffer};
+use rand::Rng;
 
-use bevy::{
-	math::Vec3Swizzles,
-	prelude::*,
-	render::{extract_resource::ExtractResource, view::RenderLayers},
+use crate::{
+	assets::Atlases,
+	enemies::spawn_biome_enemy,
+	gems::{spawn_gems, Gem},
+	mob::{CollidesWithWalls, Mob},
+	player::Player,
+	shooting::{Blast, Detonation, Projectile},
+	structures::*,
+	tilemap::*,
+	tilesim::{Simulator, TerrainChanged, TileKind},
 	utils::*,
+	Despawn,
 };
-use bevy_inspector_egui::prelude::*;
-use bevy_rapier2d::{parry::query::details::CompositeShapeAgainstAnyDistanceVisitor, prelude::*};
-
-use crate::{assets::Atlases, player::Player, structures::*, tilemap::*, tilesim::Simulator, utils::*, Despawn};
 
 pub const TILE_SIZE: f32 = 32.;
-pub const FOG_RADIUS: u32 = 17;
+pub const FOG_RADIUS: i32 = 17;
+// How bright a tile that is about to be forgotten is drawn, fully remembered tiles being at 1.
+const FORGOTTEN_BRIGHTNESS: f32 = 0.3;
+// How far away a floor tile can be for something caught in a wall to get pushed onto it.
+const PUSH_OUT_RADIUS: u32 = 8;
+// Top speed of anything wading through water.
+const WATER_MAX_SPEED: f32 = 2.5 * 60.;
+// Crystal veins are drawn as walls in this colour.
+const CRYSTAL_VEIN_TINT: Color = Color::rgb(0.5, 0.9, 1.);
 
 #[derive(Component)]
 pub struct Tile;
@@ -23,36 +37,23 @@ pub struct BackTile;
 #[derive(Component)]
 pub struct Structure;
 
+#[derive(Component)]
+pub struct Campfire;
+
+// The sprite that darkens the world around the camera.
 #[derive(Component)]
 pub struct Overlay;
 
-#[derive(Clone, Debug, Resource, InspectorOptions, ExtractResource)]
-pub struct TileManager {
-	pub is_wall: Box<[[bool; MAP_RADIUS_USIZE * 2]; MAP_RADIUS_USIZE * 2]>,
-	pub lightmap: Box<[[f32; MAP_RADIUS_USIZE * 2]; MAP_RADIUS_USIZE * 2]>,
-	pub spawned_tiles: HashSet<UVec2>,
-	pub campfires: HashSet<UVec2>,
-	pub structures: HashMap<UVec2, StructureType>,
-	pub reality_bubble: HashSet<UVec2>,
-}
-
-impl Default for TileManager {
-	fn default() -> Self {
-		return Self {
-			is_wall: Box::new([[false; MAP_RADIUS_USIZE * 2]; MAP_RADIUS_USIZE * 2]),
-			lightmap: Box::new([[0.; MAP_RADIUS_USIZE * 2]; MAP_RADIUS_USIZE * 2]),
-			spawned_tiles: default(),
-			campfires: default(),
-			structures: default(),
-			reality_bubble: default(),
-		};
-	}
+// Darkness of the tiles around the camera, one texel per tile.
+#[derive(Default, Resource)]
+pub struct Darkness {
+	handle: Handle<Image>,
 }
 
-pub fn position_to_tile_position(position: &Vec2) -> UVec2 {
-	(*position / Vec2::splat(TILE_SIZE)).round().as_uvec2()
+pub fn position_to_tile_position(position: &Vec2) -> IVec2 {
+	(*position / Vec2::splat(TILE_SIZE)).round().as_ivec2()
 }
-pub fn _tile_position_to_position(tile_position: &UVec2) -> Vec2 {
+pub fn _tile_position_to_position(tile_position: &IVec2) -> Vec2 {
 	Vec2::new(tile_position.x as f32 * TILE_SIZE, tile_position.y as f32 * TILE_SIZE)
 }
 pub fn spawn_tile(
@@ -60,13 +61,12 @@ pub fn spawn_tile(
 	asset_server: &AssetServer,
 	atlases: &Atlases,
 	simulator: &Simulator,
-	tile_position: UVec2,
+	tile_position: IVec2,
 ) {
-	let v = tile_position_rand(tile_position);
 	commands
 		.spawn(SpriteSheetBundle {
 			transform: Transform::from_xyz(tile_position.x as f32 * TILE_SIZE, tile_position.y as f32 * TILE_SIZE, 0.),
-			sprite: TextureAtlasSprite::new(1775 + v % 3 + 51 * ((v / 3) % 3)),
+			sprite: TextureAtlasSprite::new(floor_atlas_index(simulator, tile_position)),
 			texture_atlas: atlases.cave_atlas.clone(),
 			..default()
 		})
@@ -86,12 +86,12 @@ pub fn spawn_tile(
 		.insert(Tile);
 
 	// Check if campfire tile
-	if simulator.grid.campfires.contains(&tile_position) {
+	if simulator.grid.has_campfire(tile_position) {
 		spawn_campfire_sprite(commands, atlases, tile_position);
 	}
 
 	// Check if other structure tile
-	simulator.grid.structures.get(&tile_position).map(|structure_type| {
+	simulator.grid.structure(tile_position).map(|structure_type| {
 		spawn_structure_sprite(commands, asset_server, structure_type, tile_position);
 	});
 }
@@ -100,7 +100,7 @@ pub fn spawn_structure_sprite(
 	commands: &mut Commands,
 	asset_server: &AssetServer,
 	structure_type: &StructureType,
-	tile_position: UVec2,
+	tile_position: IVec2,
 ) {
 	commands
 		.spawn(SpriteBundle {
@@ -111,7 +111,7 @@ pub fn spawn_structure_sprite(
 		.insert(Structure);
 }
 
-pub fn spawn_campfire_sprite(commands: &mut Commands, atlases: &Atlases, tile_position: UVec2) {
+pub fn spawn_campfire_sprite(commands: &mut Commands, atlases: &Atlases, tile_position: IVec2) {
 	commands
 		.spawn(SpriteSheetBundle {
 			transform: Transform::from_xyz(tile_position.x as f32 * TILE_SIZE, tile_position.y as f32 * TILE_SIZE, 0.6),
@@ -119,7 +119,24 @@ pub fn spawn_campfire_sprite(commands: &mut Commands, atlases: &Atlases, tile_po
 			texture_atlas: atlases.campfire_atlas.clone(),
 			..default()
 		})
-		.insert(Structure);
+		.insert(Structure)
+		.insert(Campfire);
+}
+
+// Dim campfires as they burn down, and clear away the ones that went out or got picked up.
+pub fn update_campfire_sprites(
+	mut commands: Commands,
+	simulator: Res<Simulator>,
+	mut campfires: Query<(Entity, &Transform, &mut TextureAtlasSprite), With<Campfire>>,
+) {
+	for (entity, transform, mut sprite) in campfires.iter_mut() {
+		let tile_position = position_to_tile_position(&transform.translation.xy());
+		if simulator.grid.has_campfire(tile_position) {
+			sprite.color.set_a(0.3 + 0.7 * simulator.campfire_strength(tile_position));
+		} else {
+			commands.entity(entity).insert(Despawn);
+		}
+	}
 }
 
 pub fn spawn_tiles(
@@ -131,14 +148,10 @@ pub fn spawn_tiles(
 ) {
 	for camera in cameras.iter() {
 		let camera_tile_position = position_to_tile_position(&camera.translation.xy());
-		for x in camera_tile_position.x.saturating_sub(FOG_RADIUS)
-			..=min(MAP_RADIUS * 2 - 1, camera_tile_position.x.saturating_add(FOG_RADIUS))
-		{
-			for y in camera_tile_position.y.saturating_sub(FOG_RADIUS)
-				..=min(MAP_RADIUS * 2 - 1, camera_tile_position.y.saturating_add(FOG_RADIUS))
-			{
-				let tile_position = UVec2::new(x, y);
-				if !simulator.grid.spawned_tiles.contains(&tile_position) {
+		for x in camera_tile_position.x - FOG_RADIUS..=camera_tile_position.x + FOG_RADIUS {
+			for y in camera_tile_position.y - FOG_RADIUS..=camera_tile_position.y + FOG_RADIUS {
+				let tile_position = IVec2::new(x, y);
+				if simulator.in_bounds(tile_position) && !simulator.grid.spawned_tiles.contains(&tile_position) {
 					simulator.grid.spawned_tiles.insert(tile_position);
 					spawn_tile(&mut commands, &asset_server, &atlases, &simulator, tile_position);
 				}
@@ -147,30 +160,57 @@ pub fn spawn_tiles(
 	}
 }
 
+// Fill in the slots of prefab rooms as they come into view. Each slot only ever gets filled once.
+pub fn fill_prefab_slots(
+	mut commands: Commands,
+	mut asset_server: Res<AssetServer>,
+	mut texture_atlases: ResMut<Assets<TextureAtlas>>,
+	atlases: Res<Atlases>,
+	mut world_rng: ResMut<WorldRng>,
+	mut simulator: ResMut<Simulator>,
+	cameras: Query<&Transform, With<Camera>>,
+) {
+	for camera in cameras.iter() {
+		let camera_tile_position = position_to_tile_position(&camera.translation.xy());
+		for (loc, slot) in simulator.take_prefab_slots_near(camera_tile_position, FOG_RADIUS as u32) {
+			let position = _tile_position_to_position(&loc);
+			match slot {
+				PrefabSlot::Campfire => {
+					let fuel = simulator.config.campfire_fuel;
+					simulator.place_campfire(loc, fuel);
+					spawn_campfire_sprite(&mut commands, &atlases, loc);
+				},
+				PrefabSlot::Loot => {
+					let count = world_rng.0.gen_range(3..=5);
+					spawn_gems(&mut commands, &mut asset_server, &mut world_rng.0, count, position);
+				},
+				PrefabSlot::EnemySpawn => spawn_biome_enemy(
+					&mut commands,
+					&mut asset_server,
+					&mut texture_atlases,
+					&mut world_rng.0,
+					&simulator,
+					position,
+				),
+			}
+		}
+	}
+}
+
 pub fn despawn_tiles(
 	mut commands: Commands,
 	tiles: Query<(Entity, &Transform), With<Tile>>,
 	back_tiles: Query<(Entity, &Transform), With<BackTile>>,
 	structures: Query<(Entity, &Transform), With<Structure>>,
-	overlays: Query<(Entity, &Transform), With<Overlay>>,
 	cameras: Query<&Transform, With<Camera>>,
 	mut simulator: ResMut<Simulator>,
 ) {
 	for camera in cameras.iter() {
-		for (entity, transform) in tiles
-			.iter()
-			.chain(back_tiles.iter())
-			.chain(structures.iter())
-			.chain(overlays.iter())
-		{
+		for (entity, transform) in tiles.iter().chain(back_tiles.iter()).chain(structures.iter()) {
 			let position = transform.translation.xy();
 			let camera_tile_position = position_to_tile_position(&camera.translation.xy());
 			let tile_position = position_to_tile_position(&position);
-			if tile_position.x < camera_tile_position.x.saturating_sub(FOG_RADIUS)
-				|| tile_position.x > camera_tile_position.x.saturating_add(FOG_RADIUS)
-				|| tile_position.y < camera_tile_position.y.saturating_sub(FOG_RADIUS)
-				|| tile_position.y > camera_tile_position.y.saturating_add(FOG_RADIUS)
-			{
+			if (tile_position - camera_tile_position).abs().max_element() > FOG_RADIUS {
 				simulator.grid.spawned_tiles.remove(&tile_position);
 				commands.entity(entity).insert(Despawn);
 			}
@@ -178,48 +218,155 @@ pub fn despawn_tiles(
 	}
 }
 
+pub fn setup_darkness(mut commands: Commands, mut images: ResMut<Assets<Image>>, mut darkness: ResMut<Darkness>) {
+	let size = (2 * FOG_RADIUS + 1) as u32;
+	let mut image = Image::from_dynamic(DynamicImage::ImageRgba8(ImageBuffer::new(size, size)), true);
+	// Blend between the texels so the darkness fades smoothly from tile to tile
+	image.sampler_descriptor = ImageSampler::linear();
+	darkness.handle = images.add(image);
+	commands
+		.spawn(SpriteBundle {
+			texture: darkness.handle.clone(),
+			transform: Transform::from_xyz(0., 0., 10.),
+			sprite: Sprite {
+				custom_size: Some(Vec2::splat(size as f32 * TILE_SIZE)),
+				..default()
+			},
+			..default()
+		})
+		.insert(Overlay);
+}
+
 pub fn update_lightmap(
-	mut commands: Commands,
-	mut overlays: Query<(Entity, &Transform), With<Overlay>>,
+	darkness: Res<Darkness>,
+	mut images: ResMut<Assets<Image>>,
+	mut overlays: Query<&mut Transform, With<Overlay>>,
 	mut simulator: ResMut<Simulator>,
-	mut player: Query<&Transform, With<Player>>,
-	cameras: Query<&Transform, With<Camera>>,
+	player: Query<&Transform, (With<Player>, Without<Overlay>)>,
+	cameras: Query<&Transform, (With<Camera>, Without<Overlay>)>,
 ) {
 	let player_pos = player.single().translation.truncate();
 	simulator.recalc_lightmap(position_to_tile_position(&player_pos));
-	for (entity, transform) in overlays.iter_mut() {
+
+	if let Some(image) = images.get_mut(&darkness.handle) {
+		for camera in cameras.iter() {
+			let camera_tile_position = position_to_tile_position(&camera.translation.xy());
+			let size = 2 * FOG_RADIUS + 1;
+			// Rows of the image go from the top of the screen down
+			for (i, pixel) in image.data.chunks_exact_mut(4).enumerate() {
+				let (x, y) = (i as i32 % size, i as i32 / size);
+				let tile_position = camera_tile_position + IVec2::new(x - FOG_RADIUS, FOG_RADIUS - y);
+				let light = simulator.grid.light(tile_position);
+				pixel.copy_from_slice(&[0, 0, 0, ((1. - light) * 255.).round() as u8]);
+			}
+			// Texel centers line up with tile centers
+			for mut transform in overlays.iter_mut() {
+				transform.translation = _tile_position_to_position(&camera_tile_position).extend(10.);
+			}
+		}
+	}
+}
+
+// Walls can regrow right under whatever is standing on them. Move it onto the nearest floor instead of leaving it
+// stuck in the collider, and hurt it for getting caught.
+pub fn push_out_of_walls(
+	mut terrain_changes: EventReader<TerrainChanged>,
+	simulator: Res<Simulator>,
+	mut walkers: Query<(&mut Transform, &CollidesWithWalls, Option<&mut Player>, Option<&mut Mob>)>,
+	mut gems: Query<&mut Transform, (With<Gem>, Without<CollidesWithWalls>)>,
+) {
+	let new_walls: HashSet<IVec2> = terrain_changes
+		.iter()
+		.filter(|change| change.kind.blocks_walking())
+		.map(|change| change.pos)
+		.collect();
+	if new_walls.is_empty() {
+		return;
+	}
+	for (mut transform, collides, player, mob) in walkers.iter_mut() {
+		if push_out(&simulator, &new_walls, &mut transform) {
+			if let Some(mut player) = player {
+				player.take_damage(collides.crush_damage);
+			}
+			if let Some(mut mob) = mob {
+				mob.health -= collides.crush_damage;
+			}
+		}
+	}
+	for mut transform in gems.iter_mut() {
+		push_out(&simulator, &new_walls, &mut transform);
+	}
+}
+
+// Returns whether the transform stood on one of the new walls and got moved off it.
+fn push_out(simulator: &Simulator, new_walls: &HashSet<IVec2>, transform: &mut Transform) -> bool {
+	let tile_position = position_to_tile_position(&transform.translation.xy());
+	if !new_walls.contains(&tile_position) || !simulator.grid.blocks_walking(tile_position) {
+		return false;
+	}
+	if let Some(open) = simulator.nearest_open_cell(tile_position, PUSH_OUT_RADIUS) {
+		transform.translation = _tile_position_to_position(&open).extend(transform.translation.z);
+		return true;
+	}
+	return false;
+}
+
+// Water holds back whatever wades through it.
+pub fn wade_through_water(simulator: Res<Simulator>, mut walkers: Query<(&Transform, &mut Velocity), With<CollidesWithWalls>>) {
+	for (transform, mut velocity) in walkers.iter_mut() {
 		let tile_position = position_to_tile_position(&transform.translation.xy());
-		let (i, j) = tile_position.into();
-		commands.entity(entity).insert(Despawn);
+		if simulator.grid.tile_kind(tile_position) == TileKind::Water {
+			velocity.linvel = velocity.linvel.clamp_length_max(WATER_MAX_SPEED);
+		}
 	}
+}
 
-	for camera in cameras.iter() {
-		let camera_tile_position = position_to_tile_position(&camera.translation.xy());
-		for x in camera_tile_position.x.saturating_sub(FOG_RADIUS)
-			..=min(MAP_RADIUS * 2 - 1, camera_tile_position.x.saturating_add(FOG_RADIUS))
-		{
-			for y in camera_tile_position.y.saturating_sub(FOG_RADIUS)
-				..=min(MAP_RADIUS * 2 - 1, camera_tile_position.y.saturating_add(FOG_RADIUS))
-			{
-				let tile_position = UVec2::new(x, y);
-				commands
-					.spawn(SpriteBundle {
-						transform: Transform::from_xyz(
-							tile_position.x as f32 * TILE_SIZE,
-							tile_position.y as f32 * TILE_SIZE,
-							10.,
-						),
-						sprite: Sprite {
-							color: Color::rgba(0., 0., 0., 1. - simulator.grid.lightmap[x as usize][y as usize]),
-							custom_size: Some(Vec2::new(TILE_SIZE, TILE_SIZE)),
-							..default()
-						},
-						..default()
-					})
-					.insert(Overlay);
+// Projectiles fly through walls, but break the crystal veins they cross, which drop gems.
+pub fn projectiles_break_crystal_veins(
+	mut commands: Commands,
+	mut asset_server: Res<AssetServer>,
+	mut world_rng: ResMut<WorldRng>,
+	mut simulator: ResMut<Simulator>,
+	mut terrain_changes: EventWriter<TerrainChanged>,
+	mut detonations: EventWriter<Detonation>,
+	projectiles: Query<(Entity, &Transform, Option<&Blast>), (With<Projectile>, Without<Despawn>)>,
+) {
+	for (entity, transform, blast) in projectiles.iter() {
+		let position = transform.translation.xy();
+		if simulator.break_crystal_vein(position_to_tile_position(&position)) {
+			let count = world_rng.0.gen_range(2..=3);
+			spawn_gems(&mut commands, &mut asset_server, &mut world_rng.0, count, position);
+			commands.entity(entity).insert(Despawn);
+			if let Some(blast) = blast {
+				detonations.send(Detonation {
+					position: position,
+					radius: blast.radius,
+				});
 			}
 		}
 	}
+	terrain_changes.send_batch(simulator.drain_terrain_changes());
+}
+
+// Blasts break the walls around them, unless those are protected, so the player can dig their way out.
+// Crystal veins caught in a blast drop their gems like shot ones.
+pub fn detonations_break_walls(
+	mut commands: Commands,
+	mut asset_server: Res<AssetServer>,
+	mut world_rng: ResMut<WorldRng>,
+	mut simulator: ResMut<Simulator>,
+	mut detonations: EventReader<Detonation>,
+	mut terrain_changes: EventWriter<TerrainChanged>,
+) {
+	for detonation in detonations.iter() {
+		let center = position_to_tile_position(&detonation.position);
+		for vein in simulator.blast(center, detonation.radius) {
+			let count = world_rng.0.gen_range(2..=3);
+			let position = _tile_position_to_position(&vein);
+			spawn_gems(&mut commands, &mut asset_server, &mut world_rng.0, count, position);
+		}
+	}
+	terrain_changes.send_batch(simulator.drain_terrain_changes());
 }
 
 pub fn update_tiles(
@@ -231,7 +378,17 @@ pub fn update_tiles(
 		let tile_position = position_to_tile_position(&transform.translation.xy());
 		if simulator.grid.spawned_tiles.contains(&tile_position) {
 			*ta_sprite = TextureAtlasSprite::new(tile_atlas_index(&simulator, tile_position));
-			if simulator.grid.is_wall[tile_position.x as usize][tile_position.y as usize] {
+			// Fade tiles as they are forgotten
+			let brightness = FORGOTTEN_BRIGHTNESS + (1. - FORGOTTEN_BRIGHTNESS) * simulator.grid.memory(tile_position);
+			let kind = simulator.grid.tile_kind(tile_position);
+			let tint = if kind == TileKind::CrystalVein {
+				CRYSTAL_VEIN_TINT
+			} else {
+				Color::WHITE
+			};
+			ta_sprite.color = tint * brightness;
+			// Chasms stop walkers too, projectiles have no colliders and fly over everything
+			if kind.blocks_walking() {
 				commands.entity(entity).remove::<Sensor>();
 			} else {
 				commands.entity(entity).insert(Sensor);
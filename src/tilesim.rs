@@ -1,277 +1,1191 @@
-use bevy::{prelude::*, utils::*};
-use bevy_rapier2d::na::clamp;
-use rand::*;
+use std::{collections::VecDeque, f32::consts::PI};
 
-use crate::{structures::*, tiles::*, utils::*};
+use bevy::{prelude::*, render::extract_resource::ExtractResource, utils::*};
+use bevy_inspector_egui::prelude::*;
+use rand::{rngs::StdRng, *};
+use serde::{Deserialize, Serialize};
+
+use crate::{biomes::*, carule::*, fov::*, grid::*, simconfig::*, structures::*, utils::*};
+
+// Distance at which the light of the player or a campfire has faded out completely.
+const LIGHT_RADIUS: f32 = 8.;
 
 #[derive(Resource)]
 pub struct SimulatorTimer(pub Timer);
 
+// Something that lights up its surroundings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum LightSource {
+	Player,
+	Campfire(IVec2),
+}
+
+// What a cell of the maze is made of.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum TileKind {
+	Floor,
+	Wall,
+	// Slows down whatever wades through it
+	Water,
+	// Cannot be walked over, but can be seen and shot across
+	Chasm,
+	// Rock that breaks when shot, dropping gems
+	CrystalVein,
+	// Broken rock, walkable like floor
+	Rubble,
+}
+
+impl TileKind {
+	// Rock, as far as the cellular automaton, light and line of sight are concerned.
+	pub fn is_solid(self) -> bool {
+		matches!(self, TileKind::Wall | TileKind::CrystalVein)
+	}
+
+	// Whether players and mobs collide with it.
+	pub fn blocks_walking(self) -> bool {
+		self.is_solid() || self == TileKind::Chasm
+	}
+
+	// The kind a cell turns into when the cellular automaton makes it solid or open.
+	// Cells that already are keep their kind.
+	pub fn with_solid(self, solid: bool) -> TileKind {
+		if self.is_solid() == solid {
+			self
+		} else if solid {
+			TileKind::Wall
+		} else {
+			TileKind::Floor
+		}
+	}
+}
+
+// A cell that changed kind, during a step or by getting broken.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TerrainChanged {
+	pub pos: IVec2,
+	// Whether it is solid now
+	pub became_wall: bool,
+	pub kind: TileKind,
+}
+
+// A cell that faded from memory during a step. Unless it is protected it was generated afresh,
+// which comes with a TerrainChanged if it came out different.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CellForgotten {
+	pub pos: IVec2,
+}
+
+// Everything the simulator keeps about a tile.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Cell {
+	pub kind: TileKind,
+	// Index of the biome the cell belongs to
+	pub biome: u8,
+	// How well the cell is remembered, from 1 when just seen down to 0 when forgotten
+	pub memory: f32,
+	// Recomputed from the light sources, so not saved
+	#[serde(skip)]
+	pub light: f32,
+}
+
+impl Default for Cell {
+	// Tiles start out as solid rock.
+	fn default() -> Cell {
+		Cell {
+			kind: TileKind::Wall,
+			biome: 0,
+			memory: 0.,
+			light: 0.,
+		}
+	}
+}
+
+#[derive(Clone, Debug, Resource, InspectorOptions, ExtractResource, Serialize, Deserialize)]
+pub struct TileManager {
+	pub cells: Grid<Cell>,
+	// Fuel left in each campfire, in simulator steps
+	pub campfires: StableHashMap<IVec2, u32>,
+	// Campfires lit from one of the player's torches, which they can pick back up
+	pub placed_torches: StableHashSet<IVec2>,
+	pub structures: StableHashMap<IVec2, StructureType>,
+	// Index into the config's prefabs of the room each structure got stamped with
+	pub prefabs: StableHashMap<IVec2, u16>,
+	// Prefab slots the game has not filled in yet
+	pub prefab_slots: StableHashMap<IVec2, PrefabSlot>,
+	// Tiles that have sprites in the game, which a restored grid does not have yet
+	#[serde(skip)]
+	pub spawned_tiles: HashSet<IVec2>,
+	// Cells with some memory left
+	pub reality_bubble: StableHashSet<IVec2>,
+}
+
+impl TileManager {
+	// An infinite world starts out empty, a bounded one as width x width tiles of rock.
+	pub fn new(config: &SimulatorConfig) -> Self {
+		return Self {
+			cells: if config.infinite {
+				Grid::unbounded()
+			} else {
+				Grid::new(config.width, config.width, Cell::default())
+			},
+			campfires: default(),
+			placed_torches: default(),
+			structures: default(),
+			prefabs: default(),
+			prefab_slots: default(),
+			spawned_tiles: default(),
+			reality_bubble: default(),
+		};
+	}
+
+	pub fn is_loaded(&self, loc: IVec2) -> bool {
+		self.cells.in_bounds(loc)
+	}
+
+	// Drop the chunks of an infinite world that `keep` turns down, along with everything in them.
+	pub fn retain_chunks(&mut self, mut keep: impl FnMut(IVec2) -> bool) {
+		self.cells.retain_chunks(&mut keep);
+		self.campfires.retain(|loc, _| keep(chunk_of(*loc)));
+		self.placed_torches.retain(|loc| keep(chunk_of(*loc)));
+		self.structures.retain(|loc, _| keep(chunk_of(*loc)));
+		self.prefabs.retain(|loc, _| keep(chunk_of(*loc)));
+		self.prefab_slots.retain(|loc, _| keep(chunk_of(*loc)));
+	}
+
+	// What a tile is made of, or None if its chunk is not loaded.
+	pub fn kind(&self, loc: IVec2) -> Option<TileKind> {
+		self.cells.get(loc).map(|cell| cell.kind)
+	}
+
+	// Anything that has not been generated is solid rock.
+	pub fn tile_kind(&self, loc: IVec2) -> TileKind {
+		self.kind(loc).unwrap_or(TileKind::Wall)
+	}
+
+	// Returns whether the tile was loaded and got written.
+	pub fn set_kind(&mut self, loc: IVec2, kind: TileKind) -> bool {
+		self.cells.get_mut(loc).map(|cell| cell.kind = kind).is_some()
+	}
+
+	// Whether a tile is solid, or None if its chunk is not loaded.
+	pub fn wall(&self, loc: IVec2) -> Option<bool> {
+		self.kind(loc).map(TileKind::is_solid)
+	}
+
+	pub fn is_wall(&self, loc: IVec2) -> bool {
+		self.tile_kind(loc).is_solid()
+	}
+
+	pub fn blocks_walking(&self, loc: IVec2) -> bool {
+		self.tile_kind(loc).blocks_walking()
+	}
+
+	// Makes a tile solid or open, keeping its kind if it already is.
+	// Returns whether the tile was loaded and got written.
+	pub fn set_wall(&mut self, loc: IVec2, is_wall: bool) -> bool {
+		self.cells
+			.get_mut(loc)
+			.map(|cell| cell.kind = cell.kind.with_solid(is_wall))
+			.is_some()
+	}
+
+	// Turns a tile that is in the way into floor, leaving water and rubble be.
+	pub fn make_walkable(&mut self, loc: IVec2) -> bool {
+		if self.blocks_walking(loc) {
+			return self.set_kind(loc, TileKind::Floor);
+		}
+		return self.is_loaded(loc);
+	}
+
+	// Index of the biome a tile belongs to, or the base cave if it is not loaded.
+	pub fn biome(&self, loc: IVec2) -> usize {
+		self.cells.get(loc).map_or(0, |cell| cell.biome as usize)
+	}
+
+	pub fn light(&self, loc: IVec2) -> f32 {
+		self.cells.get(loc).map_or(0., |cell| cell.light)
+	}
+
+	// Memory strength of a tile, 0 if it is forgotten or not loaded.
+	pub fn memory(&self, loc: IVec2) -> f32 {
+		self.cells.get(loc).map_or(0., |cell| cell.memory)
+	}
+
+	// Keeps the reality bubble in sync with the memory of the tile.
+	pub fn set_memory(&mut self, loc: IVec2, memory: f32) {
+		if let Some(cell) = self.cells.get_mut(loc) {
+			cell.memory = memory;
+			if memory > 0. {
+				self.reality_bubble.insert(loc);
+			} else {
+				self.reality_bubble.remove(&loc);
+			}
+		}
+	}
+
+	pub fn has_campfire(&self, loc: IVec2) -> bool {
+		self.campfires.contains_key(&loc)
+	}
+
+	pub fn campfire_fuel(&self, loc: IVec2) -> Option<u32> {
+		self.campfires.get(&loc).copied()
+	}
+
+	pub fn campfires(&self) -> impl Iterator<Item = IVec2> + '_ {
+		self.campfires.keys().copied()
+	}
+
+	// Campfires within the square of the given radius around a tile.
+	pub fn campfires_near(&self, loc: IVec2, radius: u32) -> impl Iterator<Item = IVec2> + '_ {
+		self.campfires()
+			.filter(move |c| (*c - loc).abs().max_element() <= radius as i32)
+	}
+
+	pub fn structure(&self, loc: IVec2) -> Option<&StructureType> {
+		self.structures.get(&loc)
+	}
+
+	pub fn structure_mut(&mut self, loc: IVec2) -> Option<&mut StructureType> {
+		self.structures.get_mut(&loc)
+	}
+
+	// Returns whether the tile was loaded and the structure got placed.
+	pub fn insert_structure(&mut self, loc: IVec2, structure_type: StructureType) -> bool {
+		if !self.is_loaded(loc) {
+			return false;
+		}
+		self.structures.insert(loc, structure_type);
+		return true;
+	}
+
+	pub fn structures(&self) -> impl Iterator<Item = (IVec2, &StructureType)> + '_ {
+		self.structures.iter().map(|(loc, s)| (*loc, s))
+	}
+
+	// Structures within the square of the given radius around a tile.
+	pub fn structures_near(&self, loc: IVec2, radius: u32) -> impl Iterator<Item = IVec2> + '_ {
+		self.structures
+			.keys()
+			.copied()
+			.filter(move |s| (*s - loc).abs().max_element() <= radius as i32)
+	}
+}
+
+// Everything needed to carry on with a world later, see Simulator::snapshot.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SimulatorState {
+	pub config: SimulatorConfig,
+	pub seed: WorldSeed,
+	// The simulator's generator is reseeded with this when the snapshot is taken
+	rng_seed: u64,
+	grid: TileManager,
+}
+
 #[derive(Resource)]
 pub struct Simulator {
-	width: u32,
-	ca_params: (u32, u32),
-	radii: (u32, u32),
-	weights: (u32, u32),
-	campfire_radius: u32,
-	pub reality_params: (u32, u32),
-	despawn_prob: f32,
-	n_structures: u32,
-	structure_dist: u32,
-	structure_radius: u32,
-	boss_room_radius: u32,
+	pub config: SimulatorConfig,
+	seed: WorldSeed,
+	// Factor applied to the memory of every cell outside of the reality bubble on each step
+	memory_decay: f32,
+	// Probability that a chunk of an infinite world gets a structure
+	structure_chance: f32,
+	biomes: Vec<BiomeConfig>,
+	// Rule of every biome, in the same order
+	rules: Vec<Box<dyn CaRule>>,
+	// Tiles of every prefab in the config, in the same order
+	prefabs: Vec<Vec<(IVec2, PrefabCell)>>,
+	rng: StdRng,
+	// Cells the player can see this step, when memory follows line of sight
+	visible: HashSet<IVec2>,
+	// Light each source casts on the cells it reaches, as of the last time it was cast
+	lights: HashMap<LightSource, HashMap<IVec2, f32>>,
+	// Sources to cast again on the next lightmap update, because they moved or the walls around them changed
+	stale_lights: HashSet<LightSource>,
+	player_tile: Option<IVec2>,
+	// How many structures reserve each cell, and how many campfires keep it from being forgotten.
+	// Kept up to date as they come and go, so that checking a cell is a single lookup
+	reserved_by: HashMap<IVec2, u16>,
+	warmed_by: HashMap<IVec2, u16>,
+	// Squared distance, rounded up, below which each campfire currently keeps cells remembered
+	campfire_reach: HashMap<IVec2, i32>,
+	// What the rooms around structures hold, also where their chunks are not loaded yet
+	prefab_cells: HashMap<IVec2, PrefabCell>,
+	// Structures of an infinite world that reserve their space, with their rooms. They do so as long as any
+	// chunk they reach into is loaded, whether their own chunk is or not.
+	reserved_sites: HashMap<IVec2, Option<usize>>,
+	// What was done to the world since the last step started, until it gets drained
+	terrain_changes: Vec<TerrainChanged>,
+	forgotten_cells: Vec<CellForgotten>,
 	pub grid: TileManager,
 }
 
 impl Simulator {
-	pub fn new(
-		width: u32,
-		ca_params: (u32, u32),
-		radii: (u32, u32),
-		weights: (u32, u32),
-		campfire_radius: u32,
-		reality_params: (u32, u32),
-		half_life: u32,
-		n_structures: u32,
-		structure_dist: u32,
-		structure_radius: u32,
-		boss_room_radius: u32,
-	) -> Simulator {
+	pub fn new(config: SimulatorConfig, seed: WorldSeed) -> Simulator {
+		// Spread structures as densely as over the annulus of a bounded map
+		let (outer, inner) = (config.outer_radius as f32, config.reality_outer_radius as f32);
+		let structure_density = config.n_structures as f32 / (PI * (outer * outer - inner * inner));
 		Self {
-			width: width,
-			ca_params: ca_params,
-			radii: radii,
-			weights: weights,
-			campfire_radius: campfire_radius,
-			reality_params: reality_params,
-			despawn_prob: 1.0 - 0.5_f32.powf(1.0 / half_life as f32),
-			n_structures: n_structures,
-			structure_dist: structure_dist,
-			structure_radius: structure_radius,
-			boss_room_radius: boss_room_radius,
-			grid: TileManager::default(),
+			seed: seed,
+			memory_decay: config.memory_decay(),
+			structure_chance: (structure_density * CHUNK_AREA as f32).clamp(0., 1.),
+			biomes: config.biome_configs(),
+			rules: config
+				.biome_configs()
+				.iter()
+				.map(|biome| biome.ca_rule.build().unwrap_or_else(|e| panic!("{e}")))
+				.collect(),
+			prefabs: config
+				.prefabs
+				.iter()
+				.map(|prefab| prefab.cells().unwrap_or_else(|e| panic!("{e}")))
+				.collect(),
+			rng: seed.rng(SIMULATOR_RNG_STREAM),
+			visible: default(),
+			lights: default(),
+			stale_lights: default(),
+			player_tile: None,
+			reserved_by: default(),
+			warmed_by: default(),
+			campfire_reach: default(),
+			prefab_cells: default(),
+			reserved_sites: default(),
+			terrain_changes: Vec::new(),
+			forgotten_cells: Vec::new(),
+			grid: TileManager::new(&config),
+			config: config,
+		}
+	}
+
+	// Capture the world so that it can be restored later. The generator is reseeded from itself, so the
+	// world evolves the same way from here on whether or not it gets restored.
+	pub fn snapshot(&mut self) -> SimulatorState {
+		let rng_seed = self.rng.gen();
+		self.rng = StdRng::seed_from_u64(rng_seed);
+		SimulatorState {
+			config: self.config.clone(),
+			seed: self.seed,
+			rng_seed: rng_seed,
+			grid: self.grid.clone(),
+		}
+	}
+
+	// Pick up a world from a snapshot. Unlike a new simulator it needs no post_init.
+	pub fn restore(state: SimulatorState) -> Simulator {
+		let mut simulator = Simulator::new(state.config, state.seed);
+		simulator.rng = StdRng::seed_from_u64(state.rng_seed);
+		simulator.grid = state.grid;
+		simulator.stale_lights = simulator.grid.campfires().map(LightSource::Campfire).collect();
+		if simulator.config.infinite {
+			let chunks: Vec<IVec2> = simulator.grid.cells.chunks().collect();
+			for chunk in chunks {
+				simulator.reserve_sites_near(chunk);
+			}
+		} else {
+			let structures: Vec<(IVec2, Option<usize>)> = simulator
+				.grid
+				.structures()
+				.map(|(loc, _)| (loc, simulator.grid.prefabs.get(&loc).map(|index| *index as usize)))
+				.collect();
+			for (loc, prefab) in structures {
+				simulator.reserve_around(loc, prefab, true);
+			}
+		}
+		let campfires: Vec<IVec2> = simulator.grid.campfires().collect();
+		for loc in campfires {
+			simulator.update_campfire_reach(loc);
 		}
+		simulator
 	}
 
 	pub fn post_init(&mut self) {
+		if self.config.infinite {
+			// Only the surroundings of the spawn exist for now, the rest is generated as the player explores
+			self.stream_chunks(self.world_center());
+		} else {
+			self.generate_bounded();
+		}
+
+		if self.config.ensure_connectivity {
+			// The player always starts in the middle
+			self.ensure_connectivity(self.world_center());
+		}
+	}
+
+	fn generate_bounded(&mut self) {
+		let width = self.config.width as i32;
+
 		// Generate grid
-		for i in 0..self.width {
-			for j in 0..self.width {
-				self.grid.is_wall[i as usize][j as usize] = self.calc_new_cell(UVec2::new(i, j));
+		for i in 0..width {
+			for j in 0..width {
+				let loc = IVec2::new(i, j);
+				let biome = self.biome_index_at(loc);
+				let kind = fresh_cell(&self.config, &self.biomes[biome as usize], &mut self.rng, loc);
+				self.grid.cells.get_mut(loc).map(|cell| {
+					cell.biome = biome;
+					cell.kind = kind;
+				});
 			}
 		}
 
 		// Spawn structures
-		let structure_choices = (1usize..self.width as usize)
-			.flat_map(|i| (1usize..self.width as usize).map(move |j| (i, j)))
-			.filter(|(i, j)| {
-				!self.grid.is_wall[*i][*j]
-					&& Vec2::new(*i as f32, *j as f32).distance(self.world_center().as_vec2()) > self.reality_params.1 as f32
+		let structure_choices = (1..width)
+			.flat_map(|i| (1..width).map(move |j| IVec2::new(i, j)))
+			.filter(|loc| {
+				!self.grid.blocks_walking(*loc)
+					&& loc.as_vec2().distance(self.world_center().as_vec2()) > self.config.reality_outer_radius as f32
 			})
-			.map(|(i, j)| UVec2::new(i as u32, j as u32))
 			.collect();
 
-		self.grid.structures = HashMap::default();
-
-		for pos in poisson_disk_sample(&structure_choices, self.structure_dist as f32, self.n_structures).iter() {
-			self.grid.structures.insert(*pos, StructureType::Unspawned);
+		let sites = poisson_disk_sample(
+			&mut self.rng,
+			&structure_choices,
+			self.config.structure_dist as f32,
+			self.config.n_structures,
+		);
+		for pos in sites.iter() {
+			let prefab = choose_prefab(&mut self.rng, &self.config.prefabs);
+			self.add_structure(*pos, StructureType::Unspawned, prefab);
 		}
-		self.grid.structures.insert(self.world_center(), StructureType::SpawnTutorial);
-		self.grid.structures.insert(self.boss_room_loc(), StructureType::BossAltar);
+		self.add_structure(self.world_center(), StructureType::SpawnTutorial, None);
+		self.add_structure(self.boss_room_loc(), StructureType::BossAltar, None);
 
-		for i in 0..self.width {
-			for j in 0..self.width {
-				let loc = UVec2::new(i, j);
+		for i in 0..width {
+			for j in 0..width {
+				let loc = IVec2::new(i, j);
 				let dist_from_center = self.world_center().as_vec2().distance(loc.as_vec2());
-				if dist_from_center > self.radii.1 as f32 {
+				if dist_from_center > self.config.outer_radius as f32 {
 					continue;
 				}
-				if self
-					.grid
-					.structures
-					.iter()
-					.any(|(sv, _)| sv.as_vec2().distance(loc.as_vec2()) <= self.structure_radius as f32)
-				{
-					// Space near structures should be reserved
-					self.grid.is_wall[i as usize][j as usize] = false;
-				}
+				self.stamp(loc);
+			}
+		}
+	}
+
+	// Generate a chunk of an infinite world. Like its cells, the structures in it and the rooms reaching into it
+	// from its neighbours only depend on the seed and the chunk coordinate.
+	fn generate_chunk(&mut self, chunk: IVec2) {
+		let mut rng = self.seed.chunk_rng(chunk);
+		self.grid.cells.load_chunk(chunk, Cell::default());
+		for loc in chunk_positions(chunk) {
+			let biome = self.biome_index_at(loc);
+			let kind = fresh_cell(&self.config, &self.biomes[biome as usize], &mut rng, loc);
+			self.grid.cells.get_mut(loc).map(|cell| {
+				cell.biome = biome;
+				cell.kind = kind;
+			});
+		}
+
+		for (loc, structure_type, prefab) in self.chunk_structures(chunk) {
+			self.grid.insert_structure(loc, structure_type);
+			if let Some(index) = prefab {
+				self.grid.prefabs.insert(loc, index as u16);
+			}
+		}
+		self.reserve_sites_near(chunk);
+		for loc in chunk_positions(chunk) {
+			self.stamp(loc);
+		}
+	}
+
+	// Where a chunk of an infinite world would put a structure, with how low it rolled and which room it gets.
+	fn structure_roll(&self, chunk: IVec2) -> Option<(IVec2, f32, Option<usize>)> {
+		let mut rng = self.seed.structure_rng(chunk);
+		let size = CHUNK_SIZE as i32;
+		let site = chunk_origin(chunk) + IVec2::new(rng.gen_range(0..size), rng.gen_range(0..size));
+		let roll = rng.gen::<f32>();
+		let prefab = choose_prefab(&mut rng, &self.config.prefabs);
+		let structure_dist = self.config.structure_dist as f32;
+		let near_fixed = [self.world_center(), self.boss_room_loc()]
+			.iter()
+			.any(|loc| loc.as_vec2().distance(site.as_vec2()) < structure_dist);
+		if roll >= self.structure_chance
+			|| site.as_vec2().distance(self.world_center().as_vec2()) <= self.config.reality_outer_radius as f32
+			|| near_fixed
+		{
+			return None;
+		}
+		return Some((site, roll, prefab));
+	}
+
+	// The structure a chunk of an infinite world gets, if any. Of two sites closer than structure_dist, the one
+	// that rolled lower wins, so this does not depend on which chunks happen to be loaded.
+	fn structure_site(&self, chunk: IVec2) -> Option<(IVec2, Option<usize>)> {
+		let (site, roll, prefab) = self.structure_roll(chunk)?;
+		let structure_dist = self.config.structure_dist as f32;
+		let reach = IVec2::splat(self.config.structure_dist as i32);
+		let crowded = chunks_between(site - reach, site + reach)
+			.filter(|other| *other != chunk)
+			.filter_map(|other| self.structure_roll(other))
+			.any(|(other_site, other_roll, _)| {
+				other_roll < roll && other_site.as_vec2().distance(site.as_vec2()) < structure_dist
+			});
+		if crowded {
+			return None;
+		}
+		return Some((site, prefab));
+	}
+
+	// Structures standing in a chunk of an infinite world, with their rooms.
+	fn chunk_structures(&self, chunk: IVec2) -> Vec<(IVec2, StructureType, Option<usize>)> {
+		let mut structures = Vec::new();
+		if let Some((site, prefab)) = self.structure_site(chunk) {
+			structures.push((site, StructureType::Unspawned, prefab));
+		}
+		if chunk_of(self.world_center()) == chunk {
+			structures.push((self.world_center(), StructureType::SpawnTutorial, None));
+		}
+		if chunk_of(self.boss_room_loc()) == chunk {
+			structures.push((self.boss_room_loc(), StructureType::BossAltar, None));
+		}
+		return structures;
+	}
 
-				// Special boss room
-				let dist_from_boss_room = self.boss_room_loc().as_vec2().distance(loc.as_vec2());
-				if dist_from_boss_room < self.boss_room_radius as f32 {
-					self.grid.is_wall[i as usize][j as usize] = false;
+	// Chunks whose structures can reach into the given chunk, which are also the ones its structures can reach.
+	fn chunks_in_room_reach(&self, chunk: IVec2) -> impl Iterator<Item = IVec2> {
+		let reach = ((self.config.structure_dist + 1) / 2).max(self.config.structure_radius) as i32;
+		let origin = chunk_origin(chunk);
+		chunks_between(
+			origin - IVec2::splat(reach),
+			origin + IVec2::splat(CHUNK_SIZE as i32 - 1 + reach),
+		)
+	}
+
+	// Reserve the space of every structure that reaches into a chunk, whether its own chunk is loaded or not.
+	fn reserve_sites_near(&mut self, chunk: IVec2) {
+		for other in self.chunks_in_room_reach(chunk) {
+			for (loc, _, prefab) in self.chunk_structures(other) {
+				if !self.reserved_sites.contains_key(&loc) {
+					self.reserved_sites.insert(loc, prefab);
+					self.reserve_around(loc, prefab, true);
 				}
 			}
 		}
 	}
 
-	pub fn step(&mut self, player_pos: UVec2) {
-		// Step the cellular automaton \o/
-		for i in 0..self.width {
-			for j in 0..self.width {
-				self.grid.is_wall[i as usize][j as usize] = self.calc(UVec2::new(i, j), player_pos);
+	// Biomes only depend on the seed, so a cell can be told its biome before it is generated.
+	fn biome_index_at(&self, loc: IVec2) -> u8 {
+		biome_at(self.seed, self.config.biome_size, self.biomes.len(), loc) as u8
+	}
+
+	// Make sure every chunk the cellular automaton can read from around the player is loaded.
+	fn stream_chunks(&mut self, player_pos: IVec2) {
+		let reach = IVec2::splat((self.config.reality_outer_radius + CHUNK_SIZE) as i32);
+		for chunk in chunks_between(player_pos - reach, player_pos + reach) {
+			if !self.grid.cells.is_loaded(chunk) {
+				self.generate_chunk(chunk);
 			}
 		}
-		// Check out all of the available cells if any should be despawned
-		let mut to_remove = Vec::default();
-		let (_, outerrad) = self.reality_params;
-		let outerrad = outerrad as f32;
-		for ac in self.grid.reality_bubble.iter() {
-			let dist = ac.as_vec2().distance(player_pos.as_vec2());
-			if dist > outerrad as f32 && rand::random::<f32>() < self.despawn_prob {
-				// Despawn
-				if !self.cannot_forget(*ac) {
-					to_remove.push(ac.clone());
+	}
+
+	// Drop chunks that are out of reach of the player, hold no remembered cells and are not near a campfire.
+	// They are generated afresh if the player comes back.
+	fn drop_forgotten_chunks(&mut self, player_pos: IVec2) {
+		let reach = IVec2::splat((self.config.reality_outer_radius + CHUNK_SIZE) as i32);
+		let mut keep: HashSet<IVec2> = chunks_between(player_pos - reach, player_pos + reach).collect();
+		keep.extend(self.grid.reality_bubble.iter().map(|loc| chunk_of(*loc)));
+		let campfire_reach = IVec2::splat(self.config.campfire_radius as i32);
+		for campfire in self.grid.campfires() {
+			keep.extend(chunks_between(campfire - campfire_reach, campfire + campfire_reach));
+		}
+		self.grid.retain_chunks(|chunk| keep.contains(&chunk));
+		// Structures give up their space once no chunk they reach into is left
+		let dropped: Vec<(IVec2, Option<usize>)> = self
+			.reserved_sites
+			.iter()
+			.filter(|(loc, _)| {
+				!self
+					.chunks_in_room_reach(chunk_of(**loc))
+					.any(|chunk| self.grid.cells.is_loaded(chunk))
+			})
+			.map(|(loc, prefab)| (*loc, *prefab))
+			.collect();
+		for (loc, prefab) in dropped {
+			self.reserved_sites.remove(&loc);
+			self.reserve_around(loc, prefab, false);
+		}
+	}
+
+	pub fn step(&mut self, player_pos: IVec2) {
+		self.terrain_changes.clear();
+		self.forgotten_cells.clear();
+		if self.config.infinite {
+			self.stream_chunks(player_pos);
+		}
+		if self.config.memory_model == MemoryModel::LineOfSight {
+			self.update_visible(player_pos);
+		}
+		// Only cells in the reality bubble can change
+		let reach = self.config.reality_outer_radius as i32;
+		let (min, max) = (player_pos - IVec2::splat(reach), player_pos + IVec2::splat(reach));
+		// Step the cellular automaton \o/
+		match self.config.update_mode {
+			UpdateMode::InPlace => {
+				for i in min.x..=max.x {
+					for j in min.y..=max.y {
+						let loc = IVec2::new(i, j);
+						if self.in_bounds(loc) {
+							let is_wall = self.calc(loc, player_pos);
+							self.change_wall(loc, is_wall);
+						}
+					}
+				}
+			},
+			UpdateMode::Synchronous => {
+				// Every cell reads its neighbours from the previous generation
+				let mut next_walls = Vec::new();
+				for i in min.x..=max.x {
+					for j in min.y..=max.y {
+						let loc = IVec2::new(i, j);
+						if self.in_bounds(loc) {
+							next_walls.push((loc, self.calc(loc, player_pos)));
+						}
+					}
 				}
+				for (loc, is_wall) in next_walls {
+					self.change_wall(loc, is_wall);
+				}
+			},
+		}
+		self.burn_campfires();
+		// Fade the memory of every remembered cell that the player is not looking at
+		let mut to_remove = Vec::default();
+		let remembered: Vec<IVec2> = self.grid.reality_bubble.iter().copied().collect();
+		for ac in remembered {
+			if self.in_sight(ac, player_pos) {
+				continue;
+			}
+			// Campfires keep their surroundings fresh
+			if self.cannot_forget(ac) {
+				self.grid.set_memory(ac, 1.);
+				continue;
+			}
+			let memory = self.grid.memory(ac) * self.memory_decay;
+			if memory < self.config.forget_below {
+				to_remove.push(ac);
+			} else {
+				self.grid.set_memory(ac, memory);
 			}
 		}
 		for ac in to_remove {
-			let (i, j) = ac.into();
-			self.grid.reality_bubble.remove(&ac);
+			self.grid.set_memory(ac, 0.);
+			self.forgotten_cells.push(CellForgotten { pos: ac });
 			if !self.protected(ac) {
-				assert!(self.boss_room_loc().as_vec2().distance(ac.as_vec2()) >= self.boss_room_radius as f32);
-				self.grid.is_wall[i as usize][j as usize] = self.calc_new_cell(ac);
+				assert!(self.boss_room_loc().as_vec2().distance(ac.as_vec2()) >= self.config.boss_room_radius as f32);
+				let biome = &self.biomes[self.grid.biome(ac)];
+				let kind = fresh_cell(&self.config, biome, &mut self.rng, ac);
+				self.change_kind(ac, kind);
+			}
+		}
+		if self.config.infinite {
+			self.drop_forgotten_chunks(player_pos);
+		}
+		if self.config.ensure_connectivity {
+			self.ensure_connectivity(player_pos);
+		}
+		// Only the lights that reach a cell that changed need casting again
+		let changed: Vec<IVec2> = self.terrain_changes.iter().map(|change| change.pos).collect();
+		for loc in changed {
+			self.invalidate_lights_near(loc, LIGHT_RADIUS as u32);
+		}
+	}
+
+	// Write a cell, noting it down if that changed it.
+	fn change_kind(&mut self, loc: IVec2, kind: TileKind) {
+		let old_kind = self.grid.kind(loc);
+		if self.grid.set_kind(loc, kind) && old_kind != Some(kind) {
+			self.terrain_changes.push(TerrainChanged {
+				pos: loc,
+				became_wall: kind.is_solid(),
+				kind: kind,
+			});
+		}
+	}
+
+	// Make a cell solid or open, keeping its kind if it already is.
+	fn change_wall(&mut self, loc: IVec2, is_wall: bool) {
+		if let Some(kind) = self.grid.kind(loc) {
+			self.change_kind(loc, kind.with_solid(is_wall));
+		}
+	}
+
+	// Cells that changed since the last step started.
+	pub fn drain_terrain_changes(&mut self) -> impl Iterator<Item = TerrainChanged> + '_ {
+		self.terrain_changes.drain(..)
+	}
+
+	// Cells that were forgotten during the last step.
+	pub fn drain_forgotten_cells(&mut self) -> impl Iterator<Item = CellForgotten> + '_ {
+		self.forgotten_cells.drain(..)
+	}
+
+	// Make sure that the boss room and the nearest campfire can be walked to from the player's tile.
+	// In an infinite world only loaded chunks are searched, so targets further away are left alone.
+	pub fn ensure_connectivity(&mut self, player_pos: IVec2) {
+		let nearest_campfire = self.grid.campfires().min_by(|a, b| {
+			let da = a.as_vec2().distance_squared(player_pos.as_vec2());
+			let db = b.as_vec2().distance_squared(player_pos.as_vec2());
+			da.total_cmp(&db)
+		});
+		let boss_room_loc = self.boss_room_loc();
+		for target in std::iter::once(boss_room_loc).chain(nearest_campfire) {
+			self.carve_tunnel(player_pos, target);
+		}
+	}
+
+	// Find the path from `from` to `to` that goes through the fewest walls or chasms, and open it up.
+	// Protected cells are never carved, so this gives up if they seal the target off.
+	fn carve_tunnel(&mut self, from: IVec2, to: IVec2) {
+		if !self.in_bounds(from) || !self.in_bounds(to) {
+			return;
+		}
+		// 0-1 BFS: walking over floor is free, digging through a wall costs one
+		let mut cost: HashMap<IVec2, u32> = HashMap::default();
+		let mut prev: HashMap<IVec2, IVec2> = HashMap::default();
+		let mut queue = VecDeque::new();
+		cost.insert(from, 0);
+		queue.push_back(from);
+		while let Some(loc) = queue.pop_front() {
+			if loc == to {
+				break;
 			}
+			let loc_cost = cost[&loc];
+			for next in self.maze_neighbours(loc) {
+				let blocked = self.grid.blocks_walking(next);
+				if blocked && self.protected(next) {
+					continue;
+				}
+				let next_cost = loc_cost + blocked as u32;
+				if next_cost < cost.get(&next).copied().unwrap_or(u32::MAX) {
+					cost.insert(next, next_cost);
+					prev.insert(next, loc);
+					if blocked {
+						queue.push_back(next);
+					} else {
+						queue.push_front(next);
+					}
+				}
+			}
+		}
+		if !cost.contains_key(&to) {
+			return;
+		}
+		let mut loc = to;
+		while let Some(&p) = prev.get(&loc) {
+			if self.grid.blocks_walking(loc) {
+				self.change_kind(loc, TileKind::Floor);
+			}
+			loc = p;
 		}
 	}
 
-	fn calc(&mut self, loc: UVec2, player_pos: UVec2) -> bool {
-		let (i, j) = loc.into();
-		let (k, b) = self.ca_params;
-		let (innerrad, outerrad) = self.reality_params;
-		let innerrad = innerrad as f32;
-		let outerrad = outerrad as f32;
+	// The walkable cell nearest to `from` when walking through walls, if there is one at most `max_steps` away.
+	pub fn nearest_open_cell(&self, from: IVec2, max_steps: u32) -> Option<IVec2> {
+		let mut steps: HashMap<IVec2, u32> = HashMap::default();
+		let mut queue = VecDeque::new();
+		steps.insert(from, 0);
+		queue.push_back(from);
+		while let Some(loc) = queue.pop_front() {
+			if !self.grid.blocks_walking(loc) {
+				return Some(loc);
+			}
+			let loc_steps = steps[&loc];
+			if loc_steps == max_steps {
+				continue;
+			}
+			for next in self.maze_neighbours(loc) {
+				if !steps.contains_key(&next) {
+					steps.insert(next, loc_steps + 1);
+					queue.push_back(next);
+				}
+			}
+		}
+		return None;
+	}
+
+	// Orthogonal neighbours that lie within the outer radius of the maze, or in loaded chunks of an infinite one.
+	fn maze_neighbours(&self, loc: IVec2) -> impl Iterator<Item = IVec2> + '_ {
+		[IVec2::X, IVec2::NEG_X, IVec2::Y, IVec2::NEG_Y]
+			.into_iter()
+			.map(move |d| loc + d)
+			.filter(move |n| self.in_maze(*n))
+	}
+
+	// Within the outer radius of the maze, or in a loaded chunk of an infinite one. Cells outside of it
+	// are rock that the cellular automaton never touches.
+	fn in_maze(&self, loc: IVec2) -> bool {
+		if self.config.infinite {
+			return self.grid.is_loaded(loc);
+		}
+		let outer_radius = self.config.outer_radius as f32;
+		return loc.as_vec2().distance(self.world_center().as_vec2()) <= outer_radius;
+	}
+
+	fn calc(&mut self, loc: IVec2, player_pos: IVec2) -> bool {
+		let innerrad = self.config.reality_inner_radius as f32;
+		let outerrad = self.config.reality_outer_radius as f32;
 		// Calculate distance from player
 		let dist = loc.as_vec2().distance(player_pos.as_vec2());
-		// Update available_cells
-		if dist < outerrad && !self.grid.reality_bubble.contains(&loc) {
-			self.grid.reality_bubble.insert(loc);
-			let boss_room_loc = self.boss_room_loc();
-			// If structure, assign it a random value
-			self.grid.structures.get_mut(&loc).map(|v| {
-				*v = decide_structure_type(boss_room_loc, loc);
-			});
+		// Seeing a cell refreshes its memory
+		if self.in_sight(loc, player_pos) {
+			let first_seen = !self.grid.reality_bubble.contains(&loc);
+			self.grid.set_memory(loc, 1.);
+			if first_seen {
+				let boss_room_loc = self.boss_room_loc();
+				// If structure, assign it a random value
+				self.grid.structure_mut(loc).map(|v| {
+					*v = decide_structure_type(&mut self.rng, boss_room_loc, loc);
+				});
+			}
 		}
 		// If loc between inner_rad and outer_rad
 		if innerrad <= dist && dist <= outerrad {
 			if self.protected(loc) {
-				return self.grid.is_wall[i as usize][j as usize];
+				return self.grid.is_wall(loc);
 			}
-			// If not protected, perform GoL
+			// If not protected, perform GoL with the rule of the cell's biome
+			let rule = &self.rules[self.grid.biome(loc)];
 			let mut tot = 0;
-			for dx in -1..=1 {
-				let (i, j) = (i as i32, j as i32);
-				if 0 <= i + dx && i + dx < self.width as i32 {
-					for dy in -1..=1 {
-						if 0 <= j + dy
-							&& j + dy < self.width as i32
-							&& (dx != 0 || dy != 0) && self.grid.is_wall[(i + dx) as usize][(j + dy) as usize]
-						{
-							tot += 1;
-						}
-					}
+			for d in rule.neighbourhood() {
+				if self.grid.wall(loc + *d) == Some(true) {
+					tot += 1;
 				}
 			}
+			return rule.next_state(self.grid.is_wall(loc), tot);
+		}
+		// For cells within inner_rad, return original cell
+		return self.grid.is_wall(loc);
+	}
+
+	// Whether the player currently sees a cell, which keeps it fully remembered.
+	fn in_sight(&self, loc: IVec2, player_pos: IVec2) -> bool {
+		match self.config.memory_model {
+			MemoryModel::Proximity => loc.as_vec2().distance(player_pos.as_vec2()) < self.config.reality_outer_radius as f32,
+			MemoryModel::LineOfSight => self.visible.contains(&loc),
+		}
+	}
+
+	fn update_visible(&mut self, player_pos: IVec2) {
+		let mut visible = Vec::new();
+		let radius = self.config.reality_outer_radius as i32;
+		shadowcast(player_pos, radius, |loc| self.grid.is_wall(loc), |loc| visible.push(loc));
+		self.visible = visible.into_iter().collect();
+	}
 
-			if tot <= k {
-				return false;
+	// Light up what the player and the campfires can see, fading with distance. Walls cast shadows.
+	// Only the sources that moved or had their surroundings change are cast again, and only the cells
+	// they lit before or light now are updated.
+	pub fn recalc_lightmap(&mut self, player_pos: IVec2) {
+		if self.player_tile != Some(player_pos) {
+			self.player_tile = Some(player_pos);
+			self.stale_lights.insert(LightSource::Player);
+		}
+		let stale: Vec<LightSource> = self.stale_lights.drain().collect();
+		let mut touched = HashSet::default();
+		for source in stale {
+			if let Some(lit) = self.lights.remove(&source) {
+				touched.extend(lit.into_keys());
 			}
-			if tot >= b {
-				return true;
+			let pos = match source {
+				LightSource::Player => self.player_tile,
+				LightSource::Campfire(loc) => Some(loc).filter(|loc| self.grid.has_campfire(*loc)),
+			};
+			if let Some(pos) = pos {
+				let lit = self.cast_light(pos);
+				touched.extend(lit.keys().copied());
+				self.lights.insert(source, lit);
 			}
-			return self.grid.is_wall[i as usize][j as usize];
 		}
-		// For cells within inner_rad, return original cell
-		return self.grid.is_wall[i as usize][j as usize];
+		// The brightest source reaching a cell lights it
+		for loc in touched {
+			let sources = std::iter::once(LightSource::Player)
+				.chain(self.grid.campfires_near(loc, LIGHT_RADIUS as u32).map(LightSource::Campfire));
+			let light = sources
+				.filter_map(|source| self.lights.get(&source).and_then(|lit| lit.get(&loc)))
+				.fold(0., |x: f32, y| x.max(*y));
+			self.grid.cells.get_mut(loc).map(|cell| cell.light = light);
+		}
 	}
 
-	pub fn recalc_lightmap(&mut self, player_pos: UVec2) {
-		for i in 0..self.width {
-			for j in 0..self.width {
-				let loc = UVec2::new(i, j);
-				let dist_to_player = loc.as_vec2().distance(player_pos.as_vec2());
-				let dist_to_lights = self
-					.grid
-					.campfires
-					.iter()
-					.map(|uv| uv.as_vec2().distance(loc.as_vec2()))
-					.reduce(|x, y| x.min(y))
-					.unwrap_or(f32::INFINITY);
-				self.grid.lightmap[i as usize][j as usize] = clamp(1. - dist_to_player.min(dist_to_lights) / 8., 0., 1.);
-			}
+	fn cast_light(&self, source: IVec2) -> HashMap<IVec2, f32> {
+		let mut lit = Vec::new();
+		shadowcast(source, LIGHT_RADIUS as i32, |loc| self.grid.is_wall(loc), |loc| lit.push(loc));
+		let falloff = |loc: IVec2| (1. - loc.as_vec2().distance(source.as_vec2()) / LIGHT_RADIUS).clamp(0., 1.);
+		lit.into_iter().map(|loc| (loc, falloff(loc))).collect()
+	}
+
+	// Cast the player's light and that of the campfires around a tile again on the next lightmap update.
+	pub fn invalidate_lights_near(&mut self, loc: IVec2, radius: u32) {
+		let player_near = self
+			.player_tile
+			.map_or(true, |player| (player - loc).abs().max_element() <= radius as i32);
+		if player_near {
+			self.stale_lights.insert(LightSource::Player);
 		}
+		let campfires: Vec<IVec2> = self.grid.campfires_near(loc, radius).collect();
+		self.stale_lights.extend(campfires.into_iter().map(LightSource::Campfire));
 	}
 
-	fn cannot_forget(&self, loc: UVec2) -> bool {
-		// Check if cell is within any campfires
-		self.grid
-			.campfires
-			.iter()
-			.any(|uv| uv.as_vec2().distance(loc.as_vec2()) < self.campfire_radius as f32)
+	fn cannot_forget(&self, loc: IVec2) -> bool {
+		// Check if cell is within any campfires, whose reach shrinks as they run out of fuel
+		return self.warmed_by.contains_key(&loc);
 	}
 
-	fn protected(&self, loc: UVec2) -> bool {
-		// Check if cannot forget
-		if self.cannot_forget(loc) {
-			return true;
+	// Bring the cells a campfire keeps remembered in line with its fuel. Only does any work when that
+	// changes which cells it reaches, or when the campfire got placed or removed.
+	fn update_campfire_reach(&mut self, loc: IVec2) {
+		let reach = self.config.campfire_radius as f32 * self.campfire_strength(loc);
+		let bound = (reach * reach).ceil() as i32;
+		let old_bound = self.campfire_reach.get(&loc).copied().unwrap_or(0);
+		if bound == old_bound {
+			return;
 		}
+		// Only the ring between the old and the new reach changes hands
+		if bound > old_bound {
+			mark_ring(&mut self.warmed_by, loc, old_bound, bound, true);
+		} else {
+			mark_ring(&mut self.warmed_by, loc, bound, old_bound, false);
+		}
+		if bound > 0 {
+			self.campfire_reach.insert(loc, bound);
+		} else {
+			self.campfire_reach.remove(&loc);
+		}
+	}
+
+	// How much fuel is left in a campfire, from 1 when freshly lit or refuelled down to 0 when it goes out.
+	pub fn campfire_strength(&self, loc: IVec2) -> f32 {
+		let fuel = self.grid.campfire_fuel(loc).unwrap_or(0);
+		(fuel as f32 / self.config.campfire_fuel as f32).min(1.)
+	}
+
+	// Burn a step's worth of fuel in every campfire and put out the empty ones.
+	fn burn_campfires(&mut self) {
+		let mut burnt_out = Vec::new();
+		let mut burning = Vec::new();
+		for (loc, fuel) in self.grid.campfires.iter_mut() {
+			*fuel = fuel.saturating_sub(1);
+			if *fuel == 0 {
+				burnt_out.push(*loc);
+			} else {
+				burning.push(*loc);
+			}
+		}
+		for loc in burnt_out {
+			self.remove_campfire(loc);
+		}
+		for loc in burning {
+			self.update_campfire_reach(loc);
+		}
+	}
+
+	// Space near structures and the boss room is kept open.
+	fn reserved(&self, loc: IVec2) -> bool {
 		// Check if cell is within structure_radius of other structures
-		if self
-			.grid
-			.structures
-			.iter()
-			.any(|(uv, _)| uv.as_vec2().distance(loc.as_vec2()) <= self.structure_radius as f32)
-		{
+		if self.reserved_by.contains_key(&loc) {
 			return true;
 		}
 
 		// Check if cell is close to boss room
-		if self.boss_room_loc().as_vec2().distance(loc.as_vec2()) < self.boss_room_radius as f32 {
+		if self.boss_room_loc().as_vec2().distance(loc.as_vec2()) < self.config.boss_room_radius as f32 {
 			return true;
 		}
 		return false;
 	}
 
-	fn calc_new_cell(&self, loc: UVec2) -> bool {
-		let dist = loc.as_vec2().distance(self.world_center().as_vec2());
-		let (inner_bound, outer_bound) = self.radii;
-		if dist < inner_bound as f32 {
+	// Place a structure and reserve the space around it, unless there already is one.
+	// That space is the room of the given prefab, or a disc of structure_radius without one.
+	fn add_structure(&mut self, loc: IVec2, structure_type: StructureType, prefab: Option<usize>) {
+		if self.grid.structure(loc).is_none() && self.grid.insert_structure(loc, structure_type) {
+			if let Some(index) = prefab {
+				self.grid.prefabs.insert(loc, index as u16);
+			}
+			self.reserve_around(loc, prefab, true);
+		}
+	}
+
+	fn reserve_around(&mut self, loc: IVec2, prefab: Option<usize>, reserve: bool) {
+		let index = match prefab {
+			Some(index) => index,
+			None => {
+				let radius = self.config.structure_radius as i32;
+				mark_disc(&mut self.reserved_by, loc, radius * radius + 1, reserve);
+				return;
+			},
+		};
+		// Rooms are reserved cell by cell, including their walls
+		for &(offset, cell) in self.prefabs[index].iter() {
+			mark_disc(&mut self.reserved_by, loc + offset, 1, reserve);
+			if reserve {
+				self.prefab_cells.insert(loc + offset, cell);
+			} else {
+				self.prefab_cells.remove(&(loc + offset));
+			}
+		}
+	}
+
+	// Write what a room holds into a freshly generated cell, or open it up if it is otherwise reserved.
+	fn stamp(&mut self, loc: IVec2) {
+		match self.prefab_cells.get(&loc).copied() {
+			Some(cell) => {
+				self.grid.set_kind(loc, cell.kind());
+				if let PrefabCell::Slot(slot) = cell {
+					if self.grid.is_loaded(loc) {
+						self.grid.prefab_slots.insert(loc, slot);
+					}
+				}
+			},
+			None if self.reserved(loc) => {
+				self.grid.make_walkable(loc);
+			},
+			None => {},
+		}
+	}
+
+	// Take the prefab slots within the square of the given radius around a tile, so that the game can fill them in.
+	pub fn take_prefab_slots_near(&mut self, loc: IVec2, radius: u32) -> Vec<(IVec2, PrefabSlot)> {
+		let mut slots = Vec::new();
+		self.grid.prefab_slots.retain(|slot_loc, slot| {
+			let near = (*slot_loc - loc).abs().max_element() <= radius as i32;
+			if near {
+				slots.push((*slot_loc, *slot));
+			}
+			!near
+		});
+		return slots;
+	}
+
+	fn protected(&self, loc: IVec2) -> bool {
+		// Check if cannot forget
+		return self.cannot_forget(loc) || self.reserved(loc);
+	}
+
+	pub fn world_center(&self) -> IVec2 {
+		return self.config.world_center();
+	}
+
+	pub fn boss_room_loc(&self) -> IVec2 {
+		return self.world_center() + IVec2::new(0, (self.config.outer_radius - BOSS_ROOM_INSET) as i32);
+	}
+
+	// Swap the rule the base cave evolves with, e.g. for a different cave texture.
+	pub fn set_rule(&mut self, rule: Box<dyn CaRule>) {
+		self.rules[0] = rule;
+	}
+
+	pub fn biome(&self, loc: IVec2) -> &BiomeConfig {
+		&self.biomes[self.grid.biome(loc)]
+	}
+
+	// Light a campfire with `fuel` steps worth of fuel in it.
+	pub fn place_campfire(&mut self, loc: IVec2, fuel: u32) {
+		if self.grid.is_loaded(loc) {
+			self.grid.campfires.insert(loc, fuel);
+		}
+		self.stale_lights.insert(LightSource::Campfire(loc));
+		self.update_campfire_reach(loc);
+	}
+
+	// Fill a campfire back up to a full load of fuel. Returns whether there was a campfire there.
+	pub fn refuel_campfire(&mut self, loc: IVec2) -> bool {
+		let fuel = self.config.campfire_fuel;
+		let refuelled = self.grid.campfires.get_mut(&loc).map(|v| *v = fuel).is_some();
+		self.update_campfire_reach(loc);
+		return refuelled;
+	}
+
+	// Break a crystal vein, leaving rubble behind. Returns whether there was one to break.
+	pub fn break_crystal_vein(&mut self, loc: IVec2) -> bool {
+		if self.grid.kind(loc) != Some(TileKind::CrystalVein) {
 			return false;
 		}
-		if dist > outer_bound as f32 {
-			// If dist from centre is not within the two radii, return true
-			return true;
+		self.change_kind(loc, TileKind::Rubble);
+		self.invalidate_lights_near(loc, LIGHT_RADIUS as u32);
+		return true;
+	}
+
+	// Break the solid cells within `radius` of `center` into rubble, leaving protected ones and the rock
+	// around the maze alone. Returns where crystal veins got broken.
+	pub fn blast(&mut self, center: IVec2, radius: i32) -> Vec<IVec2> {
+		let mut veins = Vec::new();
+		let bound = radius * radius + 1;
+		for dx in -radius..=radius {
+			for dy in -radius..=radius {
+				let loc = center + IVec2::new(dx, dy);
+				if dx * dx + dy * dy >= bound || !self.in_maze(loc) || self.protected(loc) {
+					continue;
+				}
+				let kind = self.grid.tile_kind(loc);
+				if !kind.is_solid() {
+					continue;
+				}
+				if kind == TileKind::CrystalVein {
+					veins.push(loc);
+				}
+				self.change_kind(loc, TileKind::Rubble);
+			}
 		}
-		// Else, return something random weighted 0, 1
-		let (e, w) = self.weights;
-		return rand::thread_rng().gen_ratio(w, e + w);
+		self.invalidate_lights_near(center, LIGHT_RADIUS as u32 + radius as u32);
+		return veins;
 	}
 
-	fn world_center(&self) -> UVec2 {
-		return UVec2::new(self.width / 2, self.width / 2);
+	// Light a campfire from one of the player's torches, so that they can pick it back up later.
+	// Floor out every cell within `radius` steps of the center, so something can land there.
+	pub fn clear_diamond(&mut self, center: IVec2, radius: i32) {
+		for dx in -radius..=radius {
+			for dy in -radius..=radius {
+				let loc = center + IVec2::new(dx, dy);
+				if dx.abs() + dy.abs() <= radius && self.grid.blocks_walking(loc) {
+					self.change_kind(loc, TileKind::Floor);
+				}
+			}
+		}
+		self.invalidate_lights_near(center, LIGHT_RADIUS as u32 + radius as u32);
 	}
 
-	pub fn boss_room_loc(&self) -> UVec2 {
-		return self.world_center() + UVec2::new(0, self.radii.1 - 6);
+	pub fn place_torch(&mut self, loc: IVec2, fuel: u32) {
+		self.place_campfire(loc, fuel);
+		if self.grid.is_loaded(loc) {
+			self.grid.placed_torches.insert(loc);
+		}
 	}
 
-	pub fn place_campfire(&mut self, loc: UVec2) {
-		self.grid.campfires.insert(loc);
+	// Put out a torch the player placed and hand back the fuel left in it. Other campfires stay lit.
+	pub fn pick_up_torch(&mut self, loc: IVec2) -> Option<u32> {
+		if !self.grid.placed_torches.contains(&loc) {
+			return None;
+		}
+		let fuel = self.grid.campfire_fuel(loc);
+		self.remove_campfire(loc);
+		return fuel;
 	}
 
-	pub fn remove_campfire(&mut self, loc: UVec2) {
+	pub fn remove_campfire(&mut self, loc: IVec2) {
 		self.grid.campfires.remove(&loc);
+		self.grid.placed_torches.remove(&loc);
+		self.stale_lights.insert(LightSource::Campfire(loc));
+		self.update_campfire_reach(loc);
+	}
+
+	// Tiles inside the map. An infinite world only has the chunks that are currently loaded.
+	pub fn in_bounds(&self, loc: IVec2) -> bool {
+		return self.grid.is_loaded(loc);
 	}
 
-	pub fn in_bounds(&self, loc: UVec2) -> bool {
-		return loc.x < self.width && loc.y < self.width;
+	// Corners of the area the map currently covers, both inclusive.
+	pub fn extent(&self) -> (IVec2, IVec2) {
+		return self
+			.grid
+			.cells
+			.extent()
+			.unwrap_or((IVec2::ZERO, IVec2::splat(self.config.width as i32 - 1)));
 	}
 
 	pub fn debug_output(&self) {
+		let (min, max) = self.extent();
 		let mut cs = 0;
-		for i in 0..self.width {
-			for j in 0..self.width {
-				if self.grid.is_wall[i as usize][j as usize] {
+		for i in min.x..=max.x {
+			for j in min.y..=max.y {
+				if self.grid.is_wall(IVec2::new(i, j)) {
 					print!("#");
 					cs += 1;
 				} else {
@@ -283,3 +1197,142 @@ impl Simulator {
 		println!("Checksum: {cs}")
 	}
 }
+
+// Count one more, or one fewer, for every cell whose squared distance from `center` is below `bound`.
+fn mark_disc(counts: &mut HashMap<IVec2, u16>, center: IVec2, bound: i32, mark: bool) {
+	mark_ring(counts, center, 0, bound, mark);
+}
+
+// Same as mark_disc, leaving out the cells whose squared distance is below `inner_bound`.
+fn mark_ring(counts: &mut HashMap<IVec2, u16>, center: IVec2, inner_bound: i32, bound: i32, mark: bool) {
+	let r = (bound as f32).sqrt().ceil() as i32;
+	for dx in -r..=r {
+		for dy in -r..=r {
+			let dist_sq = dx * dx + dy * dy;
+			if dist_sq < inner_bound || dist_sq >= bound {
+				continue;
+			}
+			let count = counts.entry(center + IVec2::new(dx, dy)).or_default();
+			if mark {
+				*count += 1;
+			} else {
+				*count -= 1;
+				if *count == 0 {
+					counts.remove(&(center + IVec2::new(dx, dy)));
+				}
+			}
+		}
+	}
+}
+
+// Contents of a cell that is generated or forgotten.
+fn fresh_cell<R: Rng>(config: &SimulatorConfig, biome: &BiomeConfig, rng: &mut R, loc: IVec2) -> TileKind {
+	let dist = loc.as_vec2().distance(config.world_center().as_vec2());
+	let (inner_bound, outer_bound) = (config.inner_radius, config.outer_radius);
+	if dist < inner_bound as f32 {
+		return TileKind::Floor;
+	}
+	if !config.infinite && dist > outer_bound as f32 {
+		// If dist from centre is not within the two radii, return a wall
+		return TileKind::Wall;
+	}
+	// Else, pick solid or open by their weights, and then which kind of it
+	let solid = [
+		(TileKind::Wall, biome.wall_weight),
+		(TileKind::CrystalVein, biome.crystal_vein_weight),
+	];
+	let open = [
+		(TileKind::Floor, biome.floor_weight),
+		(TileKind::Water, biome.water_weight),
+		(TileKind::Chasm, biome.chasm_weight),
+		(TileKind::Rubble, biome.rubble_weight),
+	];
+	let (e, w) = (biome.open_weight(), biome.solid_weight());
+	if rng.gen_ratio(w, e + w) {
+		return pick_kind(rng, &solid);
+	}
+	return pick_kind(rng, &open);
+}
+
+// Pick a kind by weight. Leaves the generator alone when only the first kind can come up, so that maps
+// without any special tiles come out the same as before there were any.
+fn pick_kind<R: Rng>(rng: &mut R, kinds: &[(TileKind, u32)]) -> TileKind {
+	let total: u32 = kinds.iter().map(|(_, weight)| weight).sum();
+	let mut roll = if total > kinds[0].1 { rng.gen_range(0..total) } else { 0 };
+	for (kind, weight) in kinds {
+		if roll < *weight {
+			return *kind;
+		}
+		roll -= weight;
+	}
+	return kinds[0].0;
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// Walk a simulator from the world center along a fixed path, lighting a campfire on the way.
+	fn walk(config: &SimulatorConfig, seed: u64) -> Simulator {
+		let mut simulator = Simulator::new(config.clone(), WorldSeed(seed));
+		simulator.post_init();
+		let start = simulator.world_center();
+		for i in 0..40 {
+			let pos = start + IVec2::new(i / 2, i / 3);
+			if i == 10 {
+				simulator.place_campfire(pos, config.campfire_fuel);
+			}
+			simulator.step(pos);
+		}
+		return simulator;
+	}
+
+	#[test]
+	fn same_seed_same_world() {
+		for infinite in [false, true] {
+			let config = SimulatorConfig {
+				infinite: infinite,
+				..default()
+			};
+			let (a, b) = (walk(&config, 7), walk(&config, 7));
+			assert!(a.grid.cells == b.grid.cells, "cells differ, infinite: {infinite}");
+			assert_eq!(a.grid.campfires, b.grid.campfires);
+			let structures = |simulator: &Simulator| simulator.grid.structures.keys().copied().collect::<HashSet<_>>();
+			assert_eq!(structures(&a), structures(&b));
+			assert_eq!(a.grid.prefabs, b.grid.prefabs);
+		}
+	}
+
+	#[test]
+	fn chunks_do_not_depend_on_their_neighbours() {
+		// Crowd the structures, so that their sites get in each other's way
+		let config = SimulatorConfig {
+			infinite: true,
+			n_structures: 40,
+			..default()
+		};
+		let (mut a, mut b) = (
+			Simulator::new(config.clone(), WorldSeed(7)),
+			Simulator::new(config, WorldSeed(7)),
+		);
+		let center = a.world_center();
+		for chunk in chunks_between(center - IVec2::splat(80), center + IVec2::splat(80)) {
+			a.generate_chunk(chunk);
+		}
+		// Drop all but a corner, then bring back a patch without the chunks around it
+		a.drop_forgotten_chunks(center - IVec2::splat(80));
+		let patch_center = center + IVec2::new(50, 0);
+		let patch: Vec<IVec2> = chunks_between(patch_center - IVec2::splat(20), patch_center + IVec2::splat(20)).collect();
+		for chunk in patch.iter() {
+			a.generate_chunk(*chunk);
+		}
+		for chunk in patch.iter().rev() {
+			b.generate_chunk(*chunk);
+		}
+		for loc in patch.iter().flat_map(|chunk| chunk_positions(*chunk)) {
+			assert_eq!(a.grid.kind(loc), b.grid.kind(loc), "{loc}");
+			assert_eq!(a.grid.structure(loc).is_some(), b.grid.structure(loc).is_some(), "{loc}");
+			assert_eq!(a.reserved(loc), b.reserved(loc), "{loc}");
+		}
+	}
+}
@@ -1,23 +1,75 @@
-use bevy::prelude::UVec2;
-use rand::*;
+use bevy::prelude::{IVec2, Resource};
+use rand::{rngs::StdRng, *};
+use serde::{Deserialize, Serialize};
 
 pub const MINIMAP_SIZE: f32 = 200.0;
-pub const MAP_RADIUS: u32 = 100;
-pub const MAP_RADIUS_USIZE: usize = MAP_RADIUS as usize;
 pub const DEBUG_OMNISCIENCE: bool = false;
 
+// Set this environment variable to replay a particular world.
+pub const SEED_ENV_VAR: &str = "TENEBRIS_SEED";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Resource, Serialize, Deserialize)]
+pub struct WorldSeed(pub u64);
+
+impl WorldSeed {
+	pub fn from_env_or_entropy() -> WorldSeed {
+		std::env::var(SEED_ENV_VAR)
+			.ok()
+			.and_then(|s| s.trim().parse().ok())
+			.map_or_else(|| WorldSeed(thread_rng().gen()), WorldSeed)
+	}
+
+	// Derive an independent generator for each consumer, so that e.g. enemy spawns do not
+	// shift the maze layout when they consume a different amount of randomness.
+	pub fn rng(&self, stream: u64) -> StdRng {
+		StdRng::seed_from_u64(self.0 ^ stream.wrapping_mul(0x9E37_79B9_7F4A_7C15))
+	}
+
+	// Generator for the initial contents of a chunk in an infinite world.
+	// It only depends on the chunk coordinate, so a chunk looks the same whenever it is generated.
+	pub fn chunk_rng(&self, chunk: IVec2) -> StdRng {
+		self.rng(CHUNK_RNG_STREAM ^ chunk_key(chunk))
+	}
+
+	// Generator for the structure a chunk of an infinite world may get. It is separate from chunk_rng, so that
+	// the neighbours of a chunk can find out about its structure without generating the chunk.
+	pub fn structure_rng(&self, chunk: IVec2) -> StdRng {
+		self.rng(STRUCTURE_RNG_STREAM ^ chunk_key(chunk))
+	}
+}
+
+fn chunk_key(chunk: IVec2) -> u64 {
+	let key = ((chunk.x as u32 as u64) << 32) | chunk.y as u32 as u64;
+	key.rotate_left(7)
+}
+
+pub const SIMULATOR_RNG_STREAM: u64 = 1;
+pub const GAMEPLAY_RNG_STREAM: u64 = 2;
+pub const CHUNK_RNG_STREAM: u64 = 3;
+pub const STRUCTURE_RNG_STREAM: u64 = 4;
+
+// Randomness used by gameplay systems (enemies, projectiles, drops). The maze has its own generator in the Simulator.
+#[derive(Resource)]
+pub struct WorldRng(pub StdRng);
+
+impl WorldRng {
+	pub fn new(seed: WorldSeed) -> WorldRng {
+		WorldRng(seed.rng(GAMEPLAY_RNG_STREAM))
+	}
+}
+
 pub fn _todo_remove_norm(i: f32, j: f32) -> f32 {
 	(i.powf(2.0) + j.powf(2.0)).sqrt()
 }
 
-pub fn poisson_disk_sample(elems: &Vec<UVec2>, dist: f32, k: u32) -> Vec<UVec2> {
+pub fn poisson_disk_sample<R: Rng>(rng: &mut R, elems: &Vec<IVec2>, dist: f32, k: u32) -> Vec<IVec2> {
 	let n = elems.len();
 	let mut res = Vec::default();
 	for _ in 0..k {
 		let mut tries = 1000;
-		let mut a = elems[thread_rng().gen_range(0..n)];
-		while !res.iter().all(|uv: &UVec2| (*uv).as_vec2().distance(a.as_vec2()) >= dist) && tries > 0 {
-			a = elems[thread_rng().gen_range(0..n)];
+		let mut a = elems[rng.gen_range(0..n)];
+		while !res.iter().all(|uv: &IVec2| (*uv).as_vec2().distance(a.as_vec2()) >= dist) && tries > 0 {
+			a = elems[rng.gen_range(0..n)];
 			tries -= 1;
 		}
 		if tries <= 0 {